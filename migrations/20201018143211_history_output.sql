@@ -0,0 +1,3 @@
+ALTER TABLE history ADD COLUMN output TEXT NOT NULL DEFAULT '';
+
+CREATE INDEX history_unix_timestamp ON history(unix_timestamp);
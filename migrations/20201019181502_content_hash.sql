@@ -0,0 +1,4 @@
+-- blake2b hash of the file contents, NULL if the file wasn't hashed yet
+ALTER TABLE relative_path ADD COLUMN hash BLOB;
+
+CREATE INDEX relative_path_hash ON relative_path(hash);
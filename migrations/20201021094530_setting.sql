@@ -0,0 +1,5 @@
+-- daemon state that needs to survive restarts
+CREATE TABLE setting (
+    name TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL
+);
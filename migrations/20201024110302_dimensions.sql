@@ -0,0 +1,10 @@
+-- NULL if the file couldn't be decoded
+ALTER TABLE relative_path ADD COLUMN width INTEGER;
+ALTER TABLE relative_path ADD COLUMN height INTEGER;
+
+-- 1 if the file needs to be indexed again even though it didn't change, unlike clearing the
+-- hash this keeps moves and duplicates detectable until then
+ALTER TABLE relative_path ADD COLUMN reindex INTEGER NOT NULL DEFAULT 0;
+
+-- so the dimensions get filled in
+UPDATE relative_path SET reindex = 1;
@@ -0,0 +1,8 @@
+-- files that aren't images so they don't get read again until they change
+CREATE TABLE rejected_path (
+    root_id INTEGER REFERENCES root(id) NOT NULL,
+    file_path TEXT NOT NULL,
+    unix_mtime INTEGER NOT NULL,
+    unix_btime INTEGER,
+    PRIMARY KEY (root_id, file_path)
+);
@@ -0,0 +1,9 @@
+-- never picked
+CREATE TABLE banned (
+    relative_path_id INTEGER PRIMARY KEY NOT NULL REFERENCES relative_path(id) ON DELETE CASCADE
+);
+
+-- picked more often
+CREATE TABLE favorite (
+    relative_path_id INTEGER PRIMARY KEY NOT NULL REFERENCES relative_path(id) ON DELETE CASCADE
+);
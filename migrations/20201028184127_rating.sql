@@ -0,0 +1,5 @@
+-- 1 to 5 stars, NULL if unrated
+ALTER TABLE relative_path ADD COLUMN rating INTEGER;
+
+-- index everything again so embedded ratings get imported
+UPDATE relative_path SET reindex = 1;
@@ -0,0 +1,7 @@
+-- the wallpaper each output showed last so it can be restored on startup
+CREATE TABLE output_wp (
+    output TEXT PRIMARY KEY NOT NULL,
+    relative_path_id INTEGER NOT NULL REFERENCES relative_path(id) ON DELETE CASCADE,
+    -- when it was set, refresh timers continue from here
+    unix_timestamp INTEGER NOT NULL
+);
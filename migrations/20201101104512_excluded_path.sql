@@ -0,0 +1,3 @@
+-- files that exist but don't match the scan config anymore, they keep their tags and history
+-- in case the config changes back
+ALTER TABLE relative_path ADD COLUMN excluded INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,25 @@
+-- bans and favorites belong to the content of a file so they survive moves and apply to every
+-- copy, files that weren't hashed yet fall back to their path
+ALTER TABLE banned RENAME TO banned_path;
+CREATE TABLE banned (
+    hash BLOB UNIQUE,
+    relative_path_id INTEGER UNIQUE REFERENCES relative_path(id) ON DELETE CASCADE,
+    CHECK ((hash IS NULL) != (relative_path_id IS NULL))
+);
+INSERT OR IGNORE INTO banned(hash, relative_path_id)
+    SELECT relative_path.hash, CASE WHEN relative_path.hash IS NULL THEN relative_path.id END
+    FROM banned_path
+    INNER JOIN relative_path ON relative_path.id = banned_path.relative_path_id;
+DROP TABLE banned_path;
+
+ALTER TABLE favorite RENAME TO favorite_path;
+CREATE TABLE favorite (
+    hash BLOB UNIQUE,
+    relative_path_id INTEGER UNIQUE REFERENCES relative_path(id) ON DELETE CASCADE,
+    CHECK ((hash IS NULL) != (relative_path_id IS NULL))
+);
+INSERT OR IGNORE INTO favorite(hash, relative_path_id)
+    SELECT relative_path.hash, CASE WHEN relative_path.hash IS NULL THEN relative_path.id END
+    FROM favorite_path
+    INNER JOIN relative_path ON relative_path.id = favorite_path.relative_path_id;
+DROP TABLE favorite_path;
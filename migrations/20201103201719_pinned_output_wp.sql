@@ -0,0 +1,2 @@
+-- 1 if the wallpaper stays on the output until it gets unpinned
+ALTER TABLE output_wp ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
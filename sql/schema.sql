@@ -10,9 +10,31 @@ CREATE TABLE relative_path (
     unix_mtime INTEGER NOT NULL,
     -- not every file system supports btime
     unix_btime INTEGER,
+    -- blake2b hash of the file contents, NULL if the file wasn't hashed yet
+    hash BLOB,
+    -- NULL if the file couldn't be decoded
+    width INTEGER,
+    height INTEGER,
+    -- 1 if the file needs to be indexed again even though it didn't change
+    reindex INTEGER NOT NULL DEFAULT 0,
+    -- 1 to 5 stars, NULL if unrated
+    rating INTEGER,
+    -- 1 if the file exists but doesn't match the scan config, it keeps its tags and history
+    excluded INTEGER NOT NULL DEFAULT 0,
     UNIQUE (root_id, file_path)
 );
 
+CREATE INDEX relative_path_hash ON relative_path(hash);
+
+-- files that aren't images so they don't get read again until they change
+CREATE TABLE rejected_path (
+    root_id INTEGER REFERENCES root(id) NOT NULL,
+    file_path TEXT NOT NULL,
+    unix_mtime INTEGER NOT NULL,
+    unix_btime INTEGER,
+    PRIMARY KEY (root_id, file_path)
+);
+
 CREATE TABLE tag (
     id INTEGER PRIMARY KEY NOT NULL,
     name TEXT NOT NULL UNIQUE
@@ -28,5 +50,38 @@ CREATE TABLE history (
     id INTEGER PRIMARY KEY NOT NULL,
     -- could use datetime but sqlite's datetime is weird
     unix_timestamp INTEGER NOT NULL,
-    relative_path_id INTEGER NOT NULL REFERENCES relative_path(id) ON DELETE CASCADE
+    relative_path_id INTEGER NOT NULL REFERENCES relative_path(id) ON DELETE CASCADE,
+    output TEXT NOT NULL DEFAULT ''
+);
+
+CREATE INDEX history_unix_timestamp ON history(unix_timestamp);
+
+-- never picked, by content hash or by path for files that weren't hashed yet
+CREATE TABLE banned (
+    hash BLOB UNIQUE,
+    relative_path_id INTEGER UNIQUE REFERENCES relative_path(id) ON DELETE CASCADE,
+    CHECK ((hash IS NULL) != (relative_path_id IS NULL))
+);
+
+-- picked more often, keyed like banned
+CREATE TABLE favorite (
+    hash BLOB UNIQUE,
+    relative_path_id INTEGER UNIQUE REFERENCES relative_path(id) ON DELETE CASCADE,
+    CHECK ((hash IS NULL) != (relative_path_id IS NULL))
+);
+
+-- daemon state that needs to survive restarts
+CREATE TABLE setting (
+    name TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL
+);
+
+-- the wallpaper each output showed last so it can be restored on startup
+CREATE TABLE output_wp (
+    output TEXT PRIMARY KEY NOT NULL,
+    relative_path_id INTEGER NOT NULL REFERENCES relative_path(id) ON DELETE CASCADE,
+    -- when it was set, refresh timers continue from here
+    unix_timestamp INTEGER NOT NULL,
+    -- 1 if the wallpaper stays on the output until it gets unpinned
+    pinned INTEGER NOT NULL DEFAULT 0
 );
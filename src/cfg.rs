@@ -1,7 +1,16 @@
-use crate::monitor::Mode;
+use crate::{
+    cron::CronExpr,
+    data::Rating,
+    monitor::{Backend, CommandConfig, Mode},
+    schedule::{Days, Location, TimeWindow},
+    tag_expr::{self, TagExpr},
+    tag_source::{self, TagSourceKind},
+    util::glob_match,
+};
 use serde::{Deserialize, Deserializer};
 use snafu::ResultExt;
 use std::{
+    collections::BTreeMap,
     fs,
     path::{Path, PathBuf},
     time::Duration,
@@ -11,15 +20,187 @@ use time::{Date, OffsetDateTime, UtcOffset};
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
-    pub wp_dir: String,
-    //pub backend: Backend,
+    /// Shorthand for a single entry in `wp_dirs`
+    wp_dir: Option<String>,
+    #[serde(default)]
+    wp_dirs: Vec<WpDir>,
+    #[serde(default)]
+    pub backend: Backend,
+    pub command: Option<CommandConfig>,
     pub mode: Mode,
-    //pub time: TimeKind,
     #[serde(with = "humantime_serde")]
     pub rescan_interval: Duration,
+    #[serde(default, with = "humantime_serde")]
+    refresh_interval: Option<Duration>,
+    /// Cron expressions for when to change wallpapers, alongside `refresh-interval`
+    #[serde(default)]
+    refresh_at: Vec<CronExpr>,
+    pub filter: Filter,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Settings that apply at certain times of day, the first matching one wins
+    #[serde(default)]
+    pub schedule: Vec<Schedule>,
+    /// Needed for sunrise, sunset, dawn and dusk in the schedule
+    pub location: Option<Location>,
+    /// Overrides for outputs, keyed by output name or glob pattern
+    #[serde(default)]
+    pub output: BTreeMap<String, OutputConfig>,
+    #[serde(default)]
+    pub scan: ScanConfig,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct WpDir {
+    pub path: String,
+    /// How likely wallpapers get picked from this directory relative to the others
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// Narrows down the active filter for wallpapers in this directory
+    pub filter: Option<FilterOverride>,
+}
+
+fn default_weight() -> f64 {
+    1.
+}
+
+/// Which files in a wallpaper directory get indexed.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ScanConfig {
+    /// Glob patterns matched against paths relative to the wallpaper directory,
+    /// includes everything if empty
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Size in bytes or with a unit like `"50M"`
+    #[serde(deserialize_with = "deserialize_opt_size")]
+    pub max_file_size: Option<u64>,
+    /// Where tags of new files come from
+    pub tag_sources: Vec<TagSourceKind>,
+    /// How often the tags of already indexed files are queried again, never if unset
     #[serde(with = "humantime_serde")]
-    pub refresh_interval: Duration,
+    pub retag_interval: Option<Duration>,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            // partial downloads
+            exclude: vec!["*.part".to_owned(), "*.crdownload".to_owned()],
+            max_file_size: None,
+            tag_sources: tag_source::default_tag_sources(),
+            retag_interval: None,
+        }
+    }
+}
+
+impl ScanConfig {
+    pub fn accepts(&self, path: &str, size: u64) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob_match(glob, path)))
+            && !self.exclude.iter().any(|glob| glob_match(glob, path))
+            && self.max_file_size.map_or(true, |max| size <= max)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Profile {
+    #[serde(default)]
+    pub filter: FilterOverride,
+    pub mode: Option<Mode>,
+    #[serde(default, with = "humantime_serde")]
+    pub refresh_interval: Option<Duration>,
+    pub refresh_at: Option<Vec<CronExpr>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Schedule {
+    /// Like `"07:00-18:00"` or `"sunset-dusk"`, wraps around midnight if the end is before the
+    /// start
+    pub time: TimeWindow,
+    /// Like `["mon", "tue"]` or `"weekdays"`, every day if unset
+    #[serde(default)]
+    pub days: Days,
+    pub filter: Option<FilterOverride>,
+    pub mode: Option<Mode>,
+    #[serde(default, with = "humantime_serde")]
+    pub refresh_interval: Option<Duration>,
+    pub refresh_at: Option<Vec<CronExpr>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutputConfig {
+    pub filter: Option<FilterOverride>,
+    pub mode: Option<Mode>,
+    #[serde(default, with = "humantime_serde")]
+    pub refresh_interval: Option<Duration>,
+    pub refresh_at: Option<Vec<CronExpr>>,
+}
+
+/// The settings for an output with the active profile and the defaults filled in.
+pub struct Active {
     pub filter: Filter,
+    pub mode: Mode,
+    pub refresh: Refresh,
+}
+
+impl Active {
+    fn apply(
+        &mut self,
+        filter: Option<&FilterOverride>,
+        mode: Option<Mode>,
+        refresh: Option<Refresh>,
+    ) {
+        if let Some(filter) = filter {
+            self.filter = self.filter.overridden(filter);
+        }
+        if let Some(mode) = mode {
+            self.mode = mode;
+        }
+        if let Some(refresh) = refresh {
+            self.refresh = refresh;
+        }
+    }
+}
+
+/// When wallpapers change by themselves, whichever comes first.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Refresh {
+    pub interval: Option<Duration>,
+    pub at: Vec<CronExpr>,
+}
+
+impl Refresh {
+    /// Overrides setting either `refresh-interval` or `refresh-at` replace both.
+    fn overridden(interval: Option<Duration>, at: Option<&Vec<CronExpr>>) -> Option<Self> {
+        if interval.is_none() && at.is_none() {
+            None
+        } else {
+            Some(Self {
+                interval,
+                at: at.cloned().unwrap_or_default(),
+            })
+        }
+    }
+
+    /// Time from `now` until the next refresh, `None` if there is none.
+    pub fn until_next(&self, now: OffsetDateTime) -> Option<Duration> {
+        self.interval
+            .into_iter()
+            .chain(self.at.iter().filter_map(|cron| cron.until_next(now)))
+            .min()
+    }
+
+    /// Time from `now` until the first refresh after `last`, zero if that already passed.
+    pub fn until_next_since(&self, last: OffsetDateTime, now: OffsetDateTime) -> Option<Duration> {
+        let elapsed = Duration::from_secs((now.timestamp() - last.timestamp()).max(0) as u64);
+        self.until_next(last)
+            .map(|until| until.checked_sub(elapsed).unwrap_or_default())
+    }
 }
 
 const DEFAULT_CONFIG: &str = include_str!("../default_config.toml");
@@ -53,7 +234,130 @@ impl Config {
     }
 
     pub fn from_slice(slice: &[u8]) -> Result<Self, Error> {
-        toml::from_slice(slice).context(Toml)
+        let ret: Self = toml::from_slice(slice).context(Toml)?;
+        if ret.wp_dir.is_none() && ret.wp_dirs.is_empty() {
+            return Err(Error::NoWpDir);
+        }
+        if let Some(dir) = ret.wp_dirs.iter().find(|dir| !(dir.weight >= 0.)) {
+            return Err(Error::InvalidWeight {
+                path: dir.path.clone(),
+            });
+        }
+        if std::iter::once(ret.filter.favorite_weight)
+            .chain(ret.overrides().filter_map(|filter| filter.favorite_weight))
+            .any(|weight| !(weight >= 0.))
+        {
+            return Err(Error::InvalidFavoriteWeight);
+        }
+        match ret.location {
+            Some(location) if !location.is_valid() => return Err(Error::InvalidLocation),
+            None if ret.schedule.iter().any(|schedule| schedule.time.is_solar()) => {
+                return Err(Error::NoLocation)
+            }
+            _ => (),
+        }
+        Ok(ret)
+    }
+
+    /// All wallpaper directories including the one from `wp-dir`.
+    pub fn wp_dirs(&self) -> Vec<WpDir> {
+        self.wp_dir
+            .iter()
+            .map(|path| WpDir {
+                path: path.clone(),
+                weight: default_weight(),
+                filter: None,
+            })
+            .chain(self.wp_dirs.iter().cloned())
+            .collect()
+    }
+
+    /// Settings for `output` at `now`. Output overrides take precedence over the profile
+    /// and a profile over the schedule, each only replaces the settings it sets.
+    pub fn active(&self, profile: Option<&str>, output: &str, now: OffsetDateTime) -> Active {
+        let mut active = Active {
+            filter: self.filter.clone(),
+            mode: self.mode,
+            refresh: self.refresh(),
+        };
+
+        match (
+            profile.and_then(|name| self.profiles.get(name)),
+            self.active_schedule(now).map(|i| &self.schedule[i]),
+        ) {
+            (Some(profile), _) => active.apply(
+                Some(&profile.filter),
+                profile.mode,
+                Refresh::overridden(profile.refresh_interval, profile.refresh_at.as_ref()),
+            ),
+            (None, Some(schedule)) => active.apply(
+                schedule.filter.as_ref(),
+                schedule.mode,
+                Refresh::overridden(schedule.refresh_interval, schedule.refresh_at.as_ref()),
+            ),
+            (None, None) => (),
+        }
+
+        if let Some(output) = self.output_config(output) {
+            active.apply(
+                output.filter.as_ref(),
+                output.mode,
+                Refresh::overridden(output.refresh_interval, output.refresh_at.as_ref()),
+            );
+        }
+
+        active
+    }
+
+    /// The default refresh settings.
+    fn refresh(&self) -> Refresh {
+        Refresh {
+            interval: self.refresh_interval,
+            at: self.refresh_at.clone(),
+        }
+    }
+
+    /// Index of the schedule entry that applies at `now`.
+    pub fn active_schedule(&self, now: OffsetDateTime) -> Option<usize> {
+        self.schedule
+            .iter()
+            .position(|schedule| schedule.time.contains(schedule.days, now, self.location))
+    }
+
+    /// The section for `output`, exact names win over glob patterns.
+    fn output_config(&self, output: &str) -> Option<&OutputConfig> {
+        self.output.get(output).or_else(|| {
+            self.output
+                .iter()
+                .find(|(pattern, _)| glob_match(pattern, output))
+                .map(|(_, cfg)| cfg)
+        })
+    }
+
+    /// Every filter override in the config.
+    fn overrides(&self) -> impl Iterator<Item = &FilterOverride> {
+        self.profiles
+            .values()
+            .map(|profile| &profile.filter)
+            .chain(
+                self.schedule
+                    .iter()
+                    .filter_map(|schedule| schedule.filter.as_ref()),
+            )
+            .chain(self.wp_dirs.iter().filter_map(|dir| dir.filter.as_ref()))
+            .chain(
+                self.output
+                    .values()
+                    .filter_map(|output| output.filter.as_ref()),
+            )
+    }
+
+    /// Every tag expression in the config.
+    pub fn tag_exprs(&self) -> impl Iterator<Item = &TagExpr> {
+        self.filter.tags.iter().chain(
+            self.overrides()
+                .filter_map(|filter| filter.tags.as_ref().and_then(Option::as_ref)),
+        )
     }
 }
 
@@ -67,6 +371,25 @@ pub enum Error {
     Toml {
         source: toml::de::Error,
     },
+
+    #[snafu(display("Config needs either wp-dir or wp-dirs"))]
+    NoWpDir,
+
+    #[snafu(display("Weight of {} must not be negative", path))]
+    InvalidWeight {
+        path: String,
+    },
+
+    #[snafu(display("favorite-weight must not be negative"))]
+    InvalidFavoriteWeight,
+
+    #[snafu(display("Schedules with sunrise, sunset, dawn or dusk need a location"))]
+    NoLocation,
+
+    #[snafu(display(
+        "Location needs a latitude between -90 and 90 and a longitude between -180 and 180"
+    ))]
+    InvalidLocation,
 }
 
 fn deserialize_opt_date<'de, D>(de: D) -> Result<Option<OffsetDateTime>, D::Error>
@@ -86,13 +409,427 @@ where
     .transpose()
 }
 
+fn deserialize_opt_size<'de, D>(de: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Bytes(u64),
+        Str(String),
+    }
+
+    match Option::<Repr>::deserialize(de)? {
+        Some(Repr::Bytes(n)) => Ok(Some(n)),
+        Some(Repr::Str(s)) => parse_size(&s)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid file size {:?}", s))),
+        None => Ok(None),
+    }
+}
+
+/// Parses sizes like `512K` or `1.5G`, units are powers of 1024.
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("iB")
+        .or_else(|| s.strip_suffix('B'))
+        .unwrap_or(s);
+    let (num, factor) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[..s.len() - 1], 1u64 << 10),
+        'M' => (&s[..s.len() - 1], 1 << 20),
+        'G' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let num = num.trim().parse::<f64>().ok()?;
+    if num.is_finite() && num >= 0. {
+        Some((num * factor as f64) as u64)
+    } else {
+        None
+    }
+}
+
+/// Which previously shown wallpapers are excluded from being picked.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LastShown {
+    Off,
+    /// The wallpapers that are currently shown on any output
+    Current,
+    /// The last n picks over all outputs
+    Picks(u32),
+    /// Everything that was shown within this timespan
+    Within(Duration),
+}
+
+impl<'de> Deserialize<'de> for LastShown {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Enabled(bool),
+            Picks(u32),
+            Within(#[serde(with = "humantime_serde")] Duration),
+        }
+
+        Ok(match Repr::deserialize(de)? {
+            Repr::Enabled(true) => LastShown::Current,
+            Repr::Enabled(false) | Repr::Picks(0) => LastShown::Off,
+            Repr::Picks(n) => LastShown::Picks(n),
+            Repr::Within(timespan) => LastShown::Within(timespan),
+        })
+    }
+}
+
+/// Aspect ratio images need to have, within a small tolerance.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AspectRatio {
+    /// The aspect ratio of the output the wallpaper is picked for
+    Auto,
+    Ratio(f64),
+}
+
+impl<'de> Deserialize<'de> for AspectRatio {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Ratio(f64),
+            Str(String),
+        }
+
+        let invalid = |s: &str| {
+            serde::de::Error::custom(format!(
+                "Invalid aspect ratio {:?}, expected \"auto\", \"w:h\" or a number",
+                s
+            ))
+        };
+
+        let ratio = match Repr::deserialize(de)? {
+            Repr::Str(s) if s == "auto" => return Ok(AspectRatio::Auto),
+            Repr::Str(s) => {
+                let mut parts = s.splitn(2, ':');
+                match (
+                    parts.next().and_then(|w| w.trim().parse::<f64>().ok()),
+                    parts.next().and_then(|h| h.trim().parse::<f64>().ok()),
+                ) {
+                    (Some(w), Some(h)) if h != 0. => w / h,
+                    _ => return Err(invalid(&s)),
+                }
+            }
+            Repr::Ratio(ratio) => ratio,
+        };
+
+        if ratio.is_finite() && ratio > 0. {
+            Ok(AspectRatio::Ratio(ratio))
+        } else {
+            Err(invalid(&ratio.to_string()))
+        }
+    }
+}
+
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct Filter {
-    pub last_shown: bool,
-    pub tags: Vec<String>,
+    /// `true`, a number of picks or a timespan like `"2d"`
+    pub last_shown: LastShown,
+    /// A tag expression like `"nature and not nsfw"` or a list of required tags
+    #[serde(default, deserialize_with = "tag_expr::deserialize_opt_tag_expr")]
+    pub tags: Option<TagExpr>,
+    #[serde(default, deserialize_with = "deserialize_opt_date")]
+    pub from_time: Option<OffsetDateTime>,
+    #[serde(default, deserialize_with = "deserialize_opt_date")]
+    pub to_time: Option<OffsetDateTime>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    /// `"auto"`, `"16:9"` or a number like `1.5`
+    pub aspect_ratio: Option<AspectRatio>,
+    /// How much more likely favorites get picked than other wallpapers
+    #[serde(default = "default_favorite_weight")]
+    pub favorite_weight: f64,
+    /// Only wallpapers with at least this many stars, excludes unrated ones
+    pub min_rating: Option<Rating>,
+    #[serde(default)]
+    pub rating_weighting: RatingWeighting,
+}
+
+fn default_favorite_weight() -> f64 {
+    2.
+}
+
+/// Settings of a [`Filter`] that replace the ones of the filter it is applied to, all optional.
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct FilterOverride {
+    pub last_shown: Option<LastShown>,
+    /// An empty expression clears the tags of the filter it is applied to
+    #[serde(deserialize_with = "deserialize_tags_override")]
+    pub tags: Option<Option<TagExpr>>,
     #[serde(deserialize_with = "deserialize_opt_date")]
     pub from_time: Option<OffsetDateTime>,
     #[serde(deserialize_with = "deserialize_opt_date")]
     pub to_time: Option<OffsetDateTime>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub aspect_ratio: Option<AspectRatio>,
+    pub favorite_weight: Option<f64>,
+    pub min_rating: Option<Rating>,
+    pub rating_weighting: Option<RatingWeighting>,
+}
+
+fn deserialize_tags_override<'de, D>(de: D) -> Result<Option<Option<TagExpr>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    tag_expr::deserialize_opt_tag_expr(de).map(Some)
+}
+
+impl Filter {
+    /// This filter with the settings `over` sets replaced.
+    pub fn overridden(&self, over: &FilterOverride) -> Self {
+        Self {
+            last_shown: over.last_shown.unwrap_or(self.last_shown),
+            tags: over.tags.clone().unwrap_or_else(|| self.tags.clone()),
+            from_time: over.from_time.or(self.from_time),
+            to_time: over.to_time.or(self.to_time),
+            min_width: over.min_width.or(self.min_width),
+            min_height: over.min_height.or(self.min_height),
+            aspect_ratio: over.aspect_ratio.or(self.aspect_ratio),
+            favorite_weight: over.favorite_weight.unwrap_or(self.favorite_weight),
+            min_rating: over.min_rating.or(self.min_rating),
+            rating_weighting: over.rating_weighting.unwrap_or(self.rating_weighting),
+        }
+    }
+
+    /// This filter narrowed down by `over`, like for the filter of a wallpaper directory.
+    /// Tag expressions are combined with `and` and the stricter of two constraints wins,
+    /// weights come from `over` if it sets them.
+    pub fn restricted(&self, over: &FilterOverride) -> Self {
+        Self {
+            last_shown: match (self.last_shown, over.last_shown) {
+                (LastShown::Off, Some(last_shown)) => last_shown,
+                (last_shown, _) => last_shown,
+            },
+            tags: match (&self.tags, over.tags.as_ref().and_then(Option::as_ref)) {
+                (Some(tags), Some(other)) => Some(TagExpr::And(
+                    Box::new(tags.clone()),
+                    Box::new(other.clone()),
+                )),
+                (tags, other) => tags.as_ref().or(other).cloned(),
+            },
+            from_time: self.from_time.max(over.from_time),
+            to_time: match (self.to_time, over.to_time) {
+                (Some(to_time), Some(other)) => Some(to_time.min(other)),
+                (to_time, other) => to_time.or(other),
+            },
+            min_width: self.min_width.max(over.min_width),
+            min_height: self.min_height.max(over.min_height),
+            aspect_ratio: self.aspect_ratio.or(over.aspect_ratio),
+            favorite_weight: over.favorite_weight.unwrap_or(self.favorite_weight),
+            min_rating: self.min_rating.max(over.min_rating),
+            rating_weighting: over.rating_weighting.unwrap_or(self.rating_weighting),
+        }
+    }
+
+    /// How likely a wallpaper gets picked relative to an unrated non-favorite.
+    pub fn weight(&self, favorite: bool, rating: Option<Rating>) -> f64 {
+        let favorite_weight = if favorite { self.favorite_weight } else { 1. };
+        favorite_weight * self.rating_weighting.weight(rating)
+    }
+}
+
+/// How ratings change the chance of a wallpaper getting picked.
+/// Unrated wallpapers count as 3 stars.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RatingWeighting {
+    Off,
+    /// Proportional to the stars
+    Linear,
+    /// Twice as likely with every star
+    Exponential,
+}
+
+impl Default for RatingWeighting {
+    fn default() -> Self {
+        RatingWeighting::Off
+    }
+}
+
+impl RatingWeighting {
+    fn weight(self, rating: Option<Rating>) -> f64 {
+        let stars = rating.map_or(3, Rating::stars);
+        match self {
+            RatingWeighting::Off => 1.,
+            RatingWeighting::Linear => f64::from(stars) / 3.,
+            RatingWeighting::Exponential => 2f64.powi(i32::from(stars) - 3),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn config(extra: &str) -> Config {
+        let src = format!(
+            r#"
+            wp-dir = "/wp"
+            mode = "fill"
+            rescan-interval = "1h"
+            refresh-interval = "5m"
+
+            [filter]
+            last-shown = 3
+            tags = "nature"
+            min-width = 1920
+            favorite-weight = 4.0
+
+            {}
+            "#,
+            extra
+        );
+        Config::from_slice(src.as_bytes()).unwrap()
+    }
+
+    fn now() -> OffsetDateTime {
+        OffsetDateTime::unix_epoch()
+    }
+
+    #[test]
+    fn overrides_keep_unset_settings() {
+        let cfg = config(
+            r#"
+            [profiles.work.filter]
+            tags = "not nsfw"
+
+            [output.DP-1.filter]
+            min-rating = 4
+            "#,
+        );
+
+        let active = cfg.active(Some("work"), "DP-1", now());
+        assert_eq!(active.filter.last_shown, LastShown::Picks(3));
+        assert_eq!(
+            active.filter.tags,
+            Some(TagExpr::parse("not nsfw").unwrap())
+        );
+        assert_eq!(active.filter.min_width, Some(1920));
+        assert_eq!(active.filter.favorite_weight, 4.);
+        assert_eq!(active.filter.min_rating, Some(Rating::try_from(4).unwrap()));
+
+        let active = cfg.active(None, "HDMI-1", now());
+        assert_eq!(active.filter.tags, Some(TagExpr::parse("nature").unwrap()));
+        assert_eq!(active.filter.min_rating, None);
+    }
+
+    #[test]
+    fn empty_tags_override_clears_tags() {
+        let cfg = config(
+            r#"
+            [profiles.all.filter]
+            tags = ""
+            "#,
+        );
+        assert_eq!(cfg.active(Some("all"), "DP-1", now()).filter.tags, None);
+    }
+
+    #[test]
+    fn profile_without_filter() {
+        let cfg = config(
+            r#"
+            [profiles.tile]
+            mode = "tile"
+            "#,
+        );
+        let active = cfg.active(Some("tile"), "DP-1", now());
+        assert!(matches!(active.mode, Mode::Tile));
+        assert_eq!(active.filter.min_width, Some(1920));
+    }
+
+    #[test]
+    fn dir_filter_narrows_output_filter() {
+        let cfg = config(
+            r#"
+            [[wp-dirs]]
+            path = "/wp/photos"
+            [wp-dirs.filter]
+            tags = "not nsfw"
+            from-time = "2020-03-01"
+            to-time = "2020-12-31"
+            min-width = 1280
+            min-height = 1080
+            min-rating = 2
+            favorite-weight = 1.0
+
+            [output.DP-1.filter]
+            tags = "space"
+            from-time = "2020-01-01"
+            to-time = "2020-06-30"
+            min-width = 2560
+            min-rating = 4
+            "#,
+        );
+
+        let dirs = cfg.wp_dirs();
+        let dir = dirs.iter().find(|dir| dir.path == "/wp/photos").unwrap();
+        let active = cfg.active(None, "DP-1", now());
+        let filter = active.filter.restricted(dir.filter.as_ref().unwrap());
+        assert_eq!(
+            filter.tags,
+            Some(TagExpr::parse("space and not nsfw").unwrap())
+        );
+        assert_eq!(filter.last_shown, LastShown::Picks(3));
+        assert_eq!(
+            filter.from_time.map(OffsetDateTime::date),
+            Some(Date::try_from_ymd(2020, 3, 1).unwrap())
+        );
+        assert_eq!(
+            filter.to_time.map(OffsetDateTime::date),
+            Some(Date::try_from_ymd(2020, 6, 30).unwrap())
+        );
+        assert_eq!(filter.min_width, Some(2560));
+        assert_eq!(filter.min_height, Some(1080));
+        assert_eq!(filter.min_rating, Some(Rating::try_from(4).unwrap()));
+        assert_eq!(filter.favorite_weight, 1.);
+    }
+
+    #[test]
+    fn dir_filter_without_tags_keeps_active_tags() {
+        let over = FilterOverride {
+            tags: Some(None),
+            min_rating: Some(Rating::try_from(2).unwrap()),
+            ..FilterOverride::default()
+        };
+        let filter = config("").filter.restricted(&over);
+        assert_eq!(filter.tags, Some(TagExpr::parse("nature").unwrap()));
+        assert_eq!(filter.min_rating, Some(Rating::try_from(2).unwrap()));
+    }
+
+    #[test]
+    fn invalid_override_favorite_weight() {
+        let src = r#"
+            wp-dir = "/wp"
+            mode = "fill"
+            rescan-interval = "1h"
+
+            [filter]
+            last-shown = true
+            tags = []
+
+            [output.DP-1.filter]
+            favorite-weight = -1.0
+        "#;
+        assert!(matches!(
+            Config::from_slice(src.as_bytes()),
+            Err(Error::InvalidFavoriteWeight)
+        ));
+    }
 }
@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -19,4 +20,117 @@ pub enum Cmd {
 
     /// Stop changing current wallpapers
     ToggleFreeze,
+
+    /// Print previously selected wallpapers, newest first
+    History {
+        /// Only show wallpapers that were set on this output
+        #[structopt(long)]
+        output: Option<String>,
+
+        /// Only show wallpapers selected in this timespan, e.g. 2h
+        #[structopt(long)]
+        since: Option<humantime::Duration>,
+
+        /// Maximum number of entries to print
+        #[structopt(long)]
+        limit: Option<u32>,
+    },
+
+    /// Switch between filter profiles
+    Profile(ProfileCmd),
+
+    /// Query tags of already indexed wallpapers again
+    Retag {
+        /// Retag every wallpaper
+        #[structopt(long, conflicts_with = "paths", required_unless = "paths")]
+        all: bool,
+
+        /// Wallpapers or directories to retag
+        #[structopt(parse(from_os_str))]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Print wallpapers with identical content
+    Duplicates,
+
+    /// Show a wallpaper on an output until it gets refreshed
+    Set {
+        output: String,
+
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+
+    /// Keep showing a wallpaper on an output while the others keep changing
+    Pin {
+        output: String,
+
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+
+    /// Let a pinned output change again
+    Unpin { output: String },
+
+    /// Go back to the previously shown wallpaper
+    Prev {
+        /// Only go back on this output
+        output: Option<String>,
+    },
+
+    /// Go forward in the history or pick a new wallpaper at the end of it
+    Next {
+        /// Only go forward on this output
+        output: Option<String>,
+    },
+
+    /// Never show a wallpaper again
+    Ban(WpArgs),
+
+    /// Allow a banned wallpaper again
+    Unban(WpArgs),
+
+    /// Show a wallpaper more often
+    Fav(WpArgs),
+
+    /// Show a favorite as often as the others again
+    Unfav(WpArgs),
+
+    /// Give a wallpaper 1 to 5 stars
+    Rate {
+        #[structopt(possible_values = &["1", "2", "3", "4", "5"])]
+        rating: u8,
+
+        #[structopt(flatten)]
+        wp: WpArgs,
+    },
+
+    /// Remove the rating of a wallpaper
+    Unrate(WpArgs),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct WpArgs {
+    /// The wallpaper, defaults to the one that is currently shown
+    #[structopt(parse(from_os_str))]
+    pub path: Option<PathBuf>,
+
+    /// Output of the currently shown wallpaper, needed with multiple outputs
+    #[structopt(long, conflicts_with = "path")]
+    pub output: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ProfileCmd {
+    /// Print all configured profiles
+    List,
+
+    /// Print the active profile
+    Current,
+
+    /// Switch to a profile
+    Set { name: String },
+
+    /// Switch back to the default filter
+    Reset,
 }
@@ -1,9 +1,30 @@
-use crate::{cli::Cmd, rpc};
+use crate::{
+    cli::{Cmd, ProfileCmd, WpArgs},
+    data::Rating,
+    rpc::{self, WpSelector},
+};
+use anyhow::Context;
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+fn canonicalize(path: &Path) -> Result<PathBuf, anyhow::Error> {
+    std::fs::canonicalize(path).with_context(|| format!("Can't find {}", path.display()))
+}
+
+fn wp_selector(args: WpArgs) -> Result<WpSelector, anyhow::Error> {
+    Ok(match args.path {
+        Some(path) => WpSelector::Path(canonicalize(&path)?),
+        None => WpSelector::Current(args.output),
+    })
+}
 
 pub async fn run(cmd: Cmd) -> Result<(), anyhow::Error> {
     let app_paths = crate::util::AppPaths::get().unwrap();
     let mut client = rpc::connect(app_paths.rt_dir).await?;
-    let ctx = tarpc::context::current();
+    let mut ctx = tarpc::context::current();
     match cmd {
         Cmd::Rescan => {
             client.scan(ctx).await?;
@@ -31,6 +52,120 @@ pub async fn run(cmd: Cmd) -> Result<(), anyhow::Error> {
                 .unwrap()
             );
         }
+        Cmd::History {
+            output,
+            since,
+            limit,
+        } => {
+            #[derive(serde::Serialize)]
+            struct HistoryOutput {
+                output: String,
+                path: String,
+                time: String,
+            }
+
+            let offset = time::UtcOffset::current_local_offset();
+            let history = client
+                .history(ctx, output, since.map(Into::into), limit)
+                .await??
+                .into_iter()
+                .map(|ent| HistoryOutput {
+                    output: ent.output,
+                    path: ent.path,
+                    time: time::OffsetDateTime::from_unix_timestamp(ent.timestamp.as_secs())
+                        .to_offset(offset)
+                        .format("%F %T"),
+                })
+                .collect::<Vec<_>>();
+
+            println!("{}", serde_json::to_string_pretty(&history).unwrap());
+        }
+        Cmd::Profile(ProfileCmd::List) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&client.profiles(ctx).await?).unwrap()
+            );
+        }
+        Cmd::Profile(ProfileCmd::Current) => {
+            #[derive(serde::Serialize)]
+            struct CurrentProfileOutput {
+                profile: Option<String>,
+            }
+
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&CurrentProfileOutput {
+                    profile: client.current_profile(ctx).await?
+                })
+                .unwrap()
+            );
+        }
+        Cmd::Profile(ProfileCmd::Set { name }) => {
+            client.set_profile(ctx, Some(name)).await??;
+        }
+        Cmd::Profile(ProfileCmd::Reset) => {
+            client.set_profile(ctx, None).await??;
+        }
+        Cmd::Retag { all, paths } => {
+            let paths = if all {
+                None
+            } else {
+                Some(
+                    paths
+                        .iter()
+                        .map(|path| canonicalize(path))
+                        .collect::<Result<Vec<_>, _>>()?,
+                )
+            };
+            // asking every tag source about every file takes a while
+            ctx.deadline = SystemTime::now() + Duration::from_secs(60 * 60);
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&client.retag(ctx, paths).await??).unwrap()
+            );
+        }
+        Cmd::Duplicates => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&client.duplicates(ctx).await??).unwrap()
+            );
+        }
+        Cmd::Set { output, path } => {
+            client.set(ctx, output, canonicalize(&path)?).await??;
+        }
+        Cmd::Pin { output, path } => {
+            client
+                .pin(ctx, output, Some(canonicalize(&path)?))
+                .await??;
+        }
+        Cmd::Unpin { output } => {
+            client.pin(ctx, output, None).await??;
+        }
+        Cmd::Prev { output } => {
+            client.prev(ctx, output).await??;
+        }
+        Cmd::Next { output } => {
+            client.next(ctx, output).await??;
+        }
+        Cmd::Ban(args) => {
+            client.ban(ctx, wp_selector(args)?, true).await??;
+        }
+        Cmd::Unban(args) => {
+            client.ban(ctx, wp_selector(args)?, false).await??;
+        }
+        Cmd::Fav(args) => {
+            client.favorite(ctx, wp_selector(args)?, true).await??;
+        }
+        Cmd::Unfav(args) => {
+            client.favorite(ctx, wp_selector(args)?, false).await??;
+        }
+        Cmd::Rate { rating, wp } => {
+            let rating = Rating::try_from(rating)?;
+            client.rate(ctx, wp_selector(wp)?, Some(rating)).await??;
+        }
+        Cmd::Unrate(args) => {
+            client.rate(ctx, wp_selector(args)?, None).await??;
+        }
     }
     Ok(())
 }
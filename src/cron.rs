@@ -0,0 +1,306 @@
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+use time::{Date, OffsetDateTime};
+
+/// How many days ahead to look for a match, expressions like `0 0 30 2 *` never match.
+const MAX_DAYS_AHEAD: u32 = 8 * 366;
+
+/// A cron expression like `30 8 * * 1-5` with the fields minute, hour, day of month, month and
+/// day of week.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    minutes: Field,
+    hours: Field,
+    days_of_month: Field,
+    months: Field,
+    /// Sunday is 0
+    days_of_week: Field,
+}
+
+#[derive(snafu::Snafu, Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    #[snafu(display("Cron expression needs 5 fields, got {}", count))]
+    FieldCount { count: usize },
+
+    #[snafu(display(
+        "Invalid cron field {:?}, expected values between {} and {}",
+        field,
+        min,
+        max
+    ))]
+    InvalidField { field: String, min: u32, max: u32 },
+}
+
+/// The allowed values of a field as a bitset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    values: u64,
+    /// Whether the field starts with `*`, which matters for combining days of month and days
+    /// of week
+    any: bool,
+}
+
+impl Field {
+    fn parse(s: &str, min: u32, max: u32) -> Result<Self, ParseError> {
+        let invalid = || ParseError::InvalidField {
+            field: s.to_owned(),
+            min,
+            max,
+        };
+        let parse_value = |s: &str| {
+            s.parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(invalid)
+        };
+
+        let mut values = 0;
+        for part in s.split(',') {
+            let mut parts = part.splitn(2, '/');
+            let range = parts.next().unwrap_or_default();
+            let step = match parts.next() {
+                Some(step) => step
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(invalid)?,
+                None => 1,
+            };
+
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else {
+                let mut bounds = range.splitn(2, '-');
+                let start = parse_value(bounds.next().unwrap_or_default())?;
+                match bounds.next() {
+                    Some(end) => (start, parse_value(end)?),
+                    // `5/15` means every 15 starting at 5
+                    None if step != 1 => (start, max),
+                    None => (start, start),
+                }
+            };
+            if start > end {
+                return Err(invalid());
+            }
+
+            for value in (start..=end).step_by(step as usize) {
+                values |= 1 << value;
+            }
+        }
+
+        Ok(Self {
+            values,
+            any: s.starts_with('*'),
+        })
+    }
+
+    fn contains(self, value: u32) -> bool {
+        self.values & (1 << value) != 0
+    }
+}
+
+impl CronExpr {
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(ParseError::FieldCount {
+                count: fields.len(),
+            });
+        }
+
+        let mut days_of_week = Field::parse(fields[4], 0, 7)?;
+        // 7 is another sunday
+        if days_of_week.contains(7) {
+            days_of_week.values |= 1;
+        }
+
+        Ok(Self {
+            minutes: Field::parse(fields[0], 0, 59)?,
+            hours: Field::parse(fields[1], 0, 23)?,
+            days_of_month: Field::parse(fields[2], 1, 31)?,
+            months: Field::parse(fields[3], 1, 12)?,
+            days_of_week,
+        })
+    }
+
+    /// Like cron, a day matches either field if both day of month and day of week are
+    /// restricted.
+    fn matches_day(&self, date: Date) -> bool {
+        let day_of_month = self.days_of_month.contains(u32::from(date.day()));
+        let day_of_week = self
+            .days_of_week
+            .contains(u32::from(date.weekday().number_days_from_sunday()));
+        let day = match (self.days_of_month.any, self.days_of_week.any) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+
+        day && self.months.contains(u32::from(date.month()))
+    }
+
+    /// First matching minute of the day at or after `from`.
+    fn first_minute(&self, from: u32) -> Option<u32> {
+        (from..24 * 60)
+            .find(|minute| self.hours.contains(minute / 60) && self.minutes.contains(minute % 60))
+    }
+
+    /// Time from `now` until this expression matches next, strictly after the current minute.
+    /// Days are assumed to be as long as the one of `now`, so this is off around DST changes.
+    pub fn until_next(&self, now: OffsetDateTime) -> Option<Duration> {
+        let minute = u32::from(now.hour()) * 60 + u32::from(now.minute());
+        let mut date = now.date();
+        for day in 0..MAX_DAYS_AHEAD {
+            let from = if day == 0 { minute + 1 } else { 0 };
+            if self.matches_day(date) {
+                if let Some(next) = self.first_minute(from) {
+                    let minutes = u64::from(day * 24 * 60 + next - minute);
+                    return Some(Duration::from_secs(minutes * 60 - u64::from(now.second())));
+                }
+            }
+            date = date.next_day();
+        }
+
+        None
+    }
+}
+
+impl<'de> Deserialize<'de> for CronExpr {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(de)?;
+        Self::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2020-11-02 is a monday.
+    fn at(day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        Date::try_from_ymd(2020, 11, day)
+            .unwrap()
+            .try_with_hms(hour, minute, 0)
+            .unwrap()
+            .assume_utc()
+    }
+
+    fn until(expr: &str, now: OffsetDateTime) -> Option<Duration> {
+        CronExpr::parse(expr).unwrap().until_next(now)
+    }
+
+    fn minutes(minutes: u64) -> Option<Duration> {
+        Some(Duration::from_secs(minutes * 60))
+    }
+
+    #[test]
+    fn weekday_mornings() {
+        let expr = "30 8 * * 1-5";
+        assert_eq!(until(expr, at(2, 7, 0)), minutes(90));
+        // strictly after the current minute
+        assert_eq!(until(expr, at(2, 8, 30)), minutes(24 * 60));
+        // friday to monday
+        assert_eq!(until(expr, at(6, 9, 0)), minutes(3 * 24 * 60 - 30));
+        assert_eq!(until(expr, at(7, 8, 0)), minutes(2 * 24 * 60 + 30));
+        assert_eq!(
+            until(expr, at(2, 7, 0) + Duration::from_secs(20)),
+            Some(Duration::from_secs(90 * 60 - 20))
+        );
+    }
+
+    #[test]
+    fn every_15_minutes() {
+        let expr = "*/15 * * * *";
+        assert_eq!(until(expr, at(2, 10, 7)), minutes(8));
+        assert_eq!(until(expr, at(2, 10, 45)), minutes(15));
+        assert_eq!(until(expr, at(2, 10, 59)), minutes(1));
+        // over midnight
+        assert_eq!(until(expr, at(2, 23, 50)), minutes(10));
+    }
+
+    #[test]
+    fn ranges_with_steps() {
+        let field = Field::parse("1-10/3", 0, 59).unwrap();
+        let values = (0..60).filter(|&v| field.contains(v)).collect::<Vec<_>>();
+        assert_eq!(values, vec![1, 4, 7, 10]);
+
+        // 9, 13 and 17
+        assert_eq!(until("0 9-17/4 * * *", at(2, 10, 0)), minutes(3 * 60));
+        assert_eq!(until("0 9-17/4 * * *", at(2, 17, 0)), minutes(16 * 60));
+        // 5, 25 and 45
+        assert_eq!(until("5/20 * * * *", at(2, 10, 30)), minutes(15));
+        assert_eq!(until("0,30 * * * *", at(2, 10, 10)), minutes(20));
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // the 13th or a tuesday, from a saturday
+        assert_eq!(until("0 0 13 * 2", at(7, 0, 0)), minutes(3 * 24 * 60));
+        // only one of them restricted
+        assert_eq!(until("0 0 13 * *", at(7, 0, 0)), minutes(6 * 24 * 60));
+        assert_eq!(until("0 0 * * 2", at(7, 0, 0)), minutes(3 * 24 * 60));
+        // odd tuesdays, `*/2` counts as unrestricted
+        assert_eq!(until("0 0 */2 * 2", at(7, 0, 0)), minutes(10 * 24 * 60));
+        // 7 is sunday too
+        assert_eq!(until("0 12 * * 7", at(7, 12, 0)), minutes(24 * 60));
+    }
+
+    #[test]
+    fn never_matches() {
+        assert_eq!(until("0 0 30 2 *", at(2, 0, 0)), None);
+        assert_eq!(until("0 0 31 4,6,9,11 *", at(2, 0, 0)), None);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            CronExpr::parse(""),
+            Err(ParseError::FieldCount { count: 0 })
+        );
+        assert_eq!(
+            CronExpr::parse("* * * *"),
+            Err(ParseError::FieldCount { count: 4 })
+        );
+        assert_eq!(
+            CronExpr::parse("* * * * * *"),
+            Err(ParseError::FieldCount { count: 6 })
+        );
+
+        let invalid_field = |expr: &str, field: &str, min: u32, max: u32| {
+            assert_eq!(
+                CronExpr::parse(expr),
+                Err(ParseError::InvalidField {
+                    field: field.to_owned(),
+                    min,
+                    max
+                }),
+                "{}",
+                expr
+            );
+        };
+        invalid_field("60 * * * *", "60", 0, 59);
+        invalid_field("* 24 * * *", "24", 0, 23);
+        invalid_field("* * 0 * *", "0", 1, 31);
+        invalid_field("* * * 13 *", "13", 1, 12);
+        invalid_field("* * * * 8", "8", 0, 7);
+        invalid_field("5-1 * * * *", "5-1", 0, 59);
+        invalid_field("*/0 * * * *", "*/0", 0, 59);
+        invalid_field("a * * * *", "a", 0, 59);
+        invalid_field("1- * * * *", "1-", 0, 59);
+        invalid_field("1,,2 * * * *", "1,,2", 0, 59);
+    }
+
+    #[test]
+    fn deserialize() {
+        #[derive(Deserialize)]
+        struct Cfg {
+            at: Vec<CronExpr>,
+        }
+
+        let cfg = toml::from_str::<Cfg>(r#"at = ["30 8 * * 1-5", "0 */2 * * *"]"#).unwrap();
+        assert_eq!(cfg.at[0], CronExpr::parse("30 8 * * 1-5").unwrap());
+        assert!(toml::from_str::<Cfg>(r#"at = ["30 8 * *"]"#).is_err());
+    }
+}
@@ -1,19 +1,37 @@
 use crate::{
-    cfg::Config,
+    cfg::{AspectRatio, Config, Filter, FilterOverride, Refresh, ScanConfig},
+    data::{PathId, RelativePath, UnixTimestamp},
     db::{self, RootData},
-    monitor::Monitor,
+    monitor::{self, Monitor},
     rpc,
-    scan::ImageScanner,
-    util::Preempter,
+    scan::{ImageScanner, LiveIndex, RootHandle},
+    schedule::{self, Clock, SystemClock},
+    util::{preemptible_interval, preemptible_timer, Preempter},
     watch_file::FileWatcher,
 };
-use futures_util::stream::{self, Stream, StreamExt};
+use ::time::OffsetDateTime;
+use futures_util::{
+    future::{BoxFuture, FutureExt},
+    stream::{self, Stream, StreamExt},
+};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    Rng,
+};
 use snafu::ResultExt;
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     signal::unix::{signal, SignalKind},
+    stream::StreamMap,
     sync::Mutex,
     task,
+    time,
 };
 
 #[derive(snafu::Snafu, Debug)]
@@ -33,8 +51,38 @@ pub enum Error {
 
     #[snafu(context(false), display("sqlite error: {}", source))]
     Db { source: sqlx::Error },
+
+    #[snafu(display("Unknown tags in filter: {}", tags.join(", ")))]
+    UnknownTags { tags: Vec<String> },
+}
+
+async fn check_tags(pool: &sqlx::SqlitePool, cfg: &Config) -> Result<(), Error> {
+    let mut tags = cfg
+        .tag_exprs()
+        .flat_map(|expr| expr.tags())
+        .collect::<Vec<_>>();
+    tags.sort_unstable();
+    tags.dedup();
+
+    let mut cxn = pool.acquire().await?;
+    let tags = db::unknown_tags(&mut cxn, &tags).await?;
+    if tags.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::UnknownTags { tags })
+    }
+}
+
+/// Tags only exist after they were scanned, so unknown ones are no reason to reject a config.
+async fn warn_unknown_tags(pool: &sqlx::SqlitePool, cfg: &Config) {
+    if let Err(e) = check_tags(pool, cfg).await {
+        tracing::warn!("{}", e);
+    }
 }
 
+pub const PROFILE_SETTING: &str = "profile";
+pub const FROZEN_SETTING: &str = "frozen";
+
 pub async fn run() -> Result<(), Error> {
     let app_paths = crate::util::AppPaths::get().unwrap();
 
@@ -43,39 +91,76 @@ pub async fn run() -> Result<(), Error> {
     let (watch_task, mut cfg_reload) = FileWatcher::default().watch(app_paths.config_file).unwrap();
     task::spawn(watch_task);
 
-    let state = State::default();
     let server = rpc::bind(app_paths.rt_dir)?;
-    task::spawn(server.serve(state.clone()));
 
     let pool = db::open(&app_paths.db_file).await?;
-    let mut term = signal_stream(&[SignalKind::terminate(), SignalKind::interrupt()])?;
 
-    let mut image_scanner = crate::scan::ImageScanner::new();
+    warn_unknown_tags(&pool, &cfg).await;
+
+    let state = State::new(pool.clone());
+    task::spawn(server.serve(state.clone()));
+
+    let mut term = signal_stream(&[SignalKind::terminate(), SignalKind::interrupt()])?;
 
-    // TODO: make configurable
-    // FIXME: unwrap
-    let mut mon: Box<dyn Monitor> = Box::new(crate::monitor::Sway::new().await?);
+    let mut backend = (cfg.backend, cfg.command.clone());
+    let mut mon = monitor::connect(cfg.backend, cfg.command.as_ref()).await?;
     let mut display_changed = mon.display_changed().await?;
 
+    let mut roots = Vec::<Root>::new();
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    // kept across reloads so only what changed gets reset
+    let mut timers = OutputTimers::new();
+    let (mut rescan_preempt, mut rescan) = preemptible_interval(cfg.rescan_interval);
+    let mut rescan_all = false;
+    let mut retag = retag_interval(cfg.scan.retag_interval);
+
     loop {
-        let root = {
-            // FIXME: this doesn't work if scan is running
-            let mut cxn = pool.acquire().await.unwrap();
-            db::get_or_insert_root(&mut cxn, cfg.wp_dir.clone()).await?
-        };
+        if (cfg.backend, cfg.command.as_ref()) != (backend.0, backend.1.as_ref()) {
+            let new_mon = match monitor::connect(cfg.backend, cfg.command.as_ref()).await {
+                Ok(new_mon) => new_mon
+                    .display_changed()
+                    .await
+                    .map(|display_changed| (new_mon, display_changed)),
+                Err(e) => Err(e),
+            };
+            match new_mon {
+                Ok((new_mon, new_display_changed)) => {
+                    mon = new_mon;
+                    display_changed = new_display_changed;
+                    backend = (cfg.backend, cfg.command.clone());
+                    // outputs start over and get their wallpapers restored on the new backend
+                    timers = OutputTimers::new();
+                }
+                Err(e) => {
+                    tracing::error!("Can't switch to {:?} backend: {}", cfg.backend, e);
+                }
+            }
+        }
 
-        image_scanner.abort_if_root_differs(root.id()).await;
+        let known = roots.iter().map(|root| root.data.id()).collect::<Vec<_>>();
+        roots = sync_roots(&pool, &cfg, roots).await?;
+        for root in &roots {
+            if rescan_all || !known.contains(&root.data.id()) {
+                root.scanner
+                    .start_scan(&pool, root.data.clone(), cfg.scan.clone());
+            }
+        }
 
         let loop_ = ControlLoop {
             cfg_reload: &mut cfg_reload,
             display_changed: &mut display_changed,
             terminate: &mut term,
-            image_scanner: &mut image_scanner,
             pool: &pool,
             cfg: &cfg,
             state: &state,
             mon: &mut *mon,
-            root,
+            roots: &roots,
+            timers: &mut timers,
+            rescan: &mut rescan,
+            rescan_preempt: rescan_preempt.clone(),
+            retag: &mut retag,
+            clock: clock.clone(),
+            schedule: cfg.active_schedule(clock.now()),
         };
 
         tracing::debug!("Starting event loop");
@@ -83,59 +168,344 @@ pub async fn run() -> Result<(), Error> {
         match loop_.run().await {
             Ok(LoopExit::Terminate) => break Ok(()),
             Ok(LoopExit::NewCfg(new_cfg)) => {
+                if new_cfg.rescan_interval != cfg.rescan_interval {
+                    let (preempt, stream) = preemptible_interval(new_cfg.rescan_interval);
+                    rescan_preempt = preempt;
+                    rescan = stream;
+                }
+                if new_cfg.scan.retag_interval != cfg.scan.retag_interval {
+                    retag = retag_interval(new_cfg.scan.retag_interval);
+                }
+                rescan_all = new_cfg.scan != cfg.scan;
                 cfg = new_cfg;
             }
             Err(e) => {
                 break Err(e);
             }
         }
+    }
+}
+
+/// Fires every `period` starting one period from now, never if unset.
+fn retag_interval(period: Option<Duration>) -> Pin<Box<dyn Stream<Item = ()> + Send>> {
+    match period {
+        Some(period) => {
+            Box::pin(time::interval_at(time::Instant::now() + period, period).map(|_| ()))
+        }
+        None => Box::pin(stream::pending()),
+    }
+}
+
+/// A wallpaper directory and everything that keeps its index up to date.
+struct Root {
+    data: RootData,
+    weight: f64,
+    filter: Option<FilterOverride>,
+    scanner: ImageScanner,
+    live_index: Option<(ScanConfig, LiveIndex)>,
+}
+
+impl Root {
+    /// The filter for wallpapers in this directory given the active one.
+    fn filter<'f>(&self, active: &'f Filter) -> Cow<'f, Filter> {
+        match &self.filter {
+            Some(filter) => Cow::Owned(active.restricted(filter)),
+            None => Cow::Borrowed(active),
+        }
+    }
+}
+
+/// Keeps the scanners of directories that are still configured and starts new ones.
+async fn sync_roots(
+    pool: &sqlx::SqlitePool,
+    cfg: &Config,
+    mut old: Vec<Root>,
+) -> Result<Vec<Root>, Error> {
+    // FIXME: this doesn't work if scan is running
+    let mut cxn = pool.acquire().await?;
+    let mut roots = Vec::<Root>::new();
+    for dir in cfg.wp_dirs() {
+        let data = db::get_or_insert_root(&mut cxn, dir.path.clone()).await?;
+        if roots.iter().any(|root| root.data.id() == data.id()) {
+            tracing::warn!("{} is configured more than once", dir.path);
+            continue;
+        }
+
+        let mut root = match old.iter().position(|root| root.data.id() == data.id()) {
+            Some(i) => old.swap_remove(i),
+            None => Root {
+                data: data.clone(),
+                weight: dir.weight,
+                filter: None,
+                scanner: ImageScanner::new(),
+                live_index: None,
+            },
+        };
+        root.weight = dir.weight;
+        root.filter = dir.filter;
+
+        if root.live_index.as_ref().map(|(scan_cfg, _)| scan_cfg) != Some(&cfg.scan) {
+            root.live_index =
+                match root
+                    .scanner
+                    .start_live_index(pool, data.clone(), cfg.scan.clone())
+                {
+                    Ok(index) => Some((cfg.scan.clone(), index)),
+                    Err(e) => {
+                        tracing::error!("Can't watch {}: {}", data.path().display(), e);
+                        None
+                    }
+                };
+        }
+
+        roots.push(root);
+    }
+
+    for root in old {
+        root.scanner.abort().await;
+    }
+
+    Ok(roots)
+}
 
-        state.clear().await;
+/// Picks a directory by weight and a wallpaper from it.
+/// Falls back to the other directories if nothing in it matches.
+/// Files in several directories only count for the first one in `roots`.
+async fn pick_from_roots<'r>(
+    cxn: &mut sqlx::SqliteConnection,
+    roots: &'r [Root],
+    default_filter: &Filter,
+    output_ratio: Option<f64>,
+    exclude: &[PathId],
+) -> Result<Option<(&'r Root, PathId, RelativePath)>, Error> {
+    let mut candidates = (0..roots.len()).collect::<Vec<_>>();
+    while !candidates.is_empty() {
+        let i = match WeightedIndex::new(candidates.iter().map(|&i| roots[i].weight)) {
+            Ok(weights) => weights.sample(&mut rand::thread_rng()),
+            // only directories with weight 0 are left
+            Err(_) => rand::thread_rng().gen_range(0, candidates.len()),
+        };
+        let i = candidates.swap_remove(i);
+        let root = &roots[i];
+        let preferred = roots[..i]
+            .iter()
+            .map(|root| root.data.id())
+            .collect::<Vec<_>>();
+
+        let filter = root.filter(default_filter);
+        let aspect_ratio = match filter.aspect_ratio {
+            Some(AspectRatio::Ratio(ratio)) => Some(ratio),
+            Some(AspectRatio::Auto) => output_ratio,
+            None => None,
+        };
+        if let Some((id, path)) = db::pickwp(
+            cxn,
+            root.data.id(),
+            &preferred,
+            &filter,
+            aspect_ratio,
+            exclude,
+        )
+        .await?
+        {
+            return Ok(Some((root, id, path)));
+        }
     }
+
+    Ok(None)
 }
 
-#[derive(Default, Clone, derive_more::Deref)]
-pub struct State(Arc<Mutex<Option<StateInner>>>);
+#[derive(Clone, derive_more::Deref)]
+pub struct State {
+    #[deref]
+    inner: Arc<Mutex<Option<StateInner>>>,
+    pub pool: sqlx::SqlitePool,
+}
 
 impl State {
-    pub async fn store(&self, inner: StateInner) {
-        *self.0.lock().await = Some(inner);
+    fn new(pool: sqlx::SqlitePool) -> Self {
+        Self {
+            inner: Default::default(),
+            pool,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CurrentWp {
+    pub id: PathId,
+    pub path: String,
+}
+
+/// Recently shown wallpapers of an output, oldest first.
+#[derive(Debug, Default)]
+pub struct OutputHistory {
+    wps: VecDeque<CurrentWp>,
+    /// Index of the wallpaper that is shown right now
+    pos: usize,
+}
+
+impl OutputHistory {
+    const LEN: u32 = 50;
+
+    async fn load(cxn: &mut sqlx::SqliteConnection, output: &str) -> Result<Self, Error> {
+        let wps = db::fetch_output_history(cxn, output, Self::LEN)
+            .await?
+            .into_iter()
+            .rev()
+            .map(|(id, path)| CurrentWp { id, path })
+            .collect::<VecDeque<_>>();
+        Ok(Self {
+            pos: wps.len().saturating_sub(1),
+            wps,
+        })
     }
 
-    pub async fn clear(&self) {
-        *self.0.lock().await = None;
+    pub fn current(&self) -> Option<&CurrentWp> {
+        self.wps.get(self.pos)
+    }
+
+    /// Adds a newly shown wallpaper, forgetting the oldest one if full.
+    fn push(&mut self, wp: CurrentWp) {
+        if self.wps.len() >= Self::LEN as usize {
+            self.wps.pop_front();
+        }
+        self.wps.push_back(wp);
+        self.pos = self.wps.len() - 1;
+    }
+
+    /// Goes back one wallpaper, `None` if this is the oldest one.
+    pub fn prev(&mut self) -> Option<&CurrentWp> {
+        self.pos = self.pos.checked_sub(1)?;
+        self.wps.get(self.pos)
+    }
+
+    /// Goes forward one wallpaper, `None` if this is the newest one.
+    pub fn next(&mut self) -> Option<&CurrentWp> {
+        if self.pos + 1 < self.wps.len() {
+            self.pos += 1;
+            self.wps.get(self.pos)
+        } else {
+            None
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct StateInner {
-    pub current_wps: BTreeMap<String, Option<String>>,
+    pub current_wps: BTreeMap<String, Option<CurrentWp>>,
     pub frozen: bool,
     pub scan_preempt: Preempter,
-    pub refresh_preempt: Preempter,
+    pub roots: Vec<RootHandle>,
+    /// Refresh timers of all known outputs
+    pub refresh_preempts: BTreeMap<String, Preempter>,
+    /// Wallpapers that stay on their output until unpinned
+    pub pinned: BTreeMap<String, CurrentWp>,
+    /// Wallpapers to show on the next refresh of their output instead of a random one
+    pub requested: BTreeMap<String, CurrentWp>,
+    pub history: BTreeMap<String, OutputHistory>,
+    pub profile: Option<String>,
+    pub profiles: Vec<String>,
 }
 
 impl StateInner {
-    fn new(refresh_preempt: Preempter, scan_preempt: Preempter) -> Self {
+    fn new(
+        scan_preempt: Preempter,
+        roots: Vec<RootHandle>,
+        frozen: bool,
+        profile: Option<String>,
+        profiles: Vec<String>,
+    ) -> Self {
         Self {
             current_wps: Default::default(),
-            frozen: Default::default(),
+            frozen,
             scan_preempt,
-            refresh_preempt,
+            roots,
+            refresh_preempts: Default::default(),
+            pinned: Default::default(),
+            requested: Default::default(),
+            history: Default::default(),
+            profile,
+            profiles,
+        }
+    }
+
+    /// Picks new wallpapers for all outputs.
+    pub fn refresh(&mut self) {
+        for preempt in self.refresh_preempts.values_mut() {
+            preempt.try_preempt();
+        }
+    }
+
+    /// Picks a new wallpaper for `output`.
+    pub fn refresh_output(&mut self, output: &str) {
+        if let Some(preempt) = self.refresh_preempts.get_mut(output) {
+            preempt.try_preempt();
         }
     }
 }
 
+/// One refresh timer per output.
+struct OutputTimers {
+    timers: StreamMap<String, Box<dyn Stream<Item = ()> + Unpin + Send>>,
+    refreshes: BTreeMap<String, Refresh>,
+}
+
+impl OutputTimers {
+    fn new() -> Self {
+        Self {
+            timers: StreamMap::new(),
+            refreshes: BTreeMap::new(),
+        }
+    }
+
+    /// Continues from the refresh at `last` if there was one.
+    fn start(
+        &mut self,
+        output: &str,
+        refresh: Refresh,
+        clock: Arc<dyn Clock>,
+        last: Option<OffsetDateTime>,
+    ) -> Preempter {
+        let next = refresh.clone();
+        let mut first = last.map(|last| next.until_next_since(last, clock.now()));
+        let (preempt, timer) =
+            preemptible_timer(move || first.take().unwrap_or_else(|| next.until_next(clock.now())));
+        self.timers.insert(output.to_owned(), Box::new(timer));
+        self.refreshes.insert(output.to_owned(), refresh);
+        preempt
+    }
+
+    fn stop(&mut self, output: &str) {
+        self.timers.remove(output);
+        self.refreshes.remove(output);
+    }
+
+    fn refresh(&self, output: &str) -> Option<&Refresh> {
+        self.refreshes.get(output)
+    }
+
+    fn outputs(&self) -> impl Iterator<Item = &String> {
+        self.refreshes.keys()
+    }
+}
+
 struct ControlLoop<'a, Reload, Terminate> {
     cfg_reload: &'a mut Reload,
     terminate: &'a mut Terminate,
-    image_scanner: &'a mut ImageScanner,
     pool: &'a sqlx::SqlitePool,
     cfg: &'a Config,
     state: &'a State,
     mon: &'a mut dyn Monitor,
     display_changed: &'a mut (dyn Stream<Item = Result<(), crate::monitor::Error>> + Unpin),
-    root: RootData,
+    roots: &'a [Root],
+    timers: &'a mut OutputTimers,
+    rescan: &'a mut (dyn Stream<Item = ()> + Unpin),
+    rescan_preempt: Preempter,
+    retag: &'a mut (dyn Stream<Item = ()> + Unpin),
+    clock: Arc<dyn Clock>,
+    /// The schedule entry that applied when last checked
+    schedule: Option<usize>,
 }
 
 impl<'a, Reload, Terminate> ControlLoop<'a, Reload, Terminate>
@@ -143,65 +513,362 @@ where
     Reload: Stream<Item = Vec<u8>> + Unpin,
     Terminate: Stream<Item = ()> + Unpin,
 {
-    async fn pickwp(&mut self) -> Result<(), Error> {
-        if let Some(state) = self.state.lock().await.as_mut() {
-            if state.frozen {
-                return Ok(());
-            }
+    async fn pickwp(&mut self, output: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        let state = match state.as_mut() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+
+        // explicitly chosen wallpapers are shown even when frozen
+        let requested = state
+            .pinned
+            .get(output)
+            .cloned()
+            .or_else(|| state.requested.remove(output));
+        if requested.is_none() && state.frozen {
+            return Ok(());
+        }
 
-            let mut cxn = self.pool.acquire().await.unwrap();
-            state.current_wps.clear();
-            for monitor in self.mon.idents().await? {
-                let ent = match db::pickwp(&mut cxn, self.root.id(), &self.cfg.filter).await? {
-                    Some((_, path)) => {
-                        let absolute_path = self.root.root(&path);
-
-                        self.mon
-                            .set_wallpaper(self.cfg.mode, &monitor, &absolute_path)
-                            .await?;
-
-                        tracing::info!(
-                            monitor = monitor.as_str(),
-                            path = absolute_path.as_str(),
-                            "Set wp"
-                        );
-                        Some(absolute_path)
+        let active = self
+            .cfg
+            .active(state.profile.as_deref(), output, self.clock.now());
+        let mut cxn = self.pool.acquire().await?;
+
+        let ent = match requested {
+            Some(wp) => Some(wp),
+            None => {
+                // don't show the same wallpaper on multiple outputs
+                let exclude = state
+                    .current_wps
+                    .iter()
+                    .filter(|(other, _)| other.as_str() != output)
+                    .filter_map(|(_, wp)| wp.as_ref().map(|wp| wp.id))
+                    .collect::<Vec<_>>();
+
+                let needs_output_ratio = self.roots.iter().any(|root| {
+                    root.filter(&active.filter).aspect_ratio == Some(AspectRatio::Auto)
+                });
+                let output_ratio = if needs_output_ratio {
+                    match self.mon.resolution(output).await? {
+                        Some((width, height)) if height != 0 => {
+                            Some(f64::from(width) / f64::from(height))
+                        }
+                        _ => {
+                            tracing::warn!(
+                                monitor = output,
+                                "Unknown output size, ignoring aspect-ratio"
+                            );
+                            None
+                        }
                     }
-                    None => {
-                        tracing::info!(monitor = monitor.as_str(), "No wp found for");
+                } else {
+                    None
+                };
+
+                let mut picked =
+                    pick_from_roots(&mut cxn, self.roots, &active.filter, output_ratio, &exclude)
+                        .await?;
+                if picked.is_none() && output_ratio.is_some() {
+                    tracing::debug!(monitor = output, "No wp matches the output's aspect ratio");
+                    picked = pick_from_roots(&mut cxn, self.roots, &active.filter, None, &exclude)
+                        .await?;
+                }
+
+                picked.map(|(root, id, path)| CurrentWp {
+                    id,
+                    path: root.data.root(&path),
+                })
+            }
+        };
+
+        match &ent {
+            Some(wp) => {
+                self.mon
+                    .set_wallpaper(active.mode, output, &wp.path)
+                    .await?;
+                let pinned = state.pinned.contains_key(output);
+                db::store_output_wp(&mut cxn, output, wp.id, UnixTimestamp::now(), pinned).await?;
+
+                // moving through the history or refreshing a pin shows the same wallpaper again
+                let history = state.history.entry(output.to_owned()).or_default();
+                if history.current().map(|current| current.id) != Some(wp.id) {
+                    db::insert_history(&mut cxn, wp.id, output, UnixTimestamp::now()).await?;
+                    history.push(wp.clone());
+                }
+
+                tracing::info!(monitor = output, path = wp.path.as_str(), "Set wp");
+            }
+            None => {
+                tracing::info!(monitor = output, "No wp found for");
+            }
+        }
+        state.current_wps.insert(output.to_owned(), ent);
+
+        // the profile might have been switched
+        if self.timers.refresh(output) != Some(&active.refresh) {
+            let preempt = self
+                .timers
+                .start(output, active.refresh, self.clock.clone(), None);
+            state.refresh_preempts.insert(output.to_owned(), preempt);
+        }
+
+        Ok(())
+    }
+
+    async fn pick(&mut self, output: &str) {
+        if let Err(e) = self.pickwp(output).await {
+            tracing::error!("{}", e);
+        }
+    }
+
+    /// Picks for `output` and every other output whose timer fired by now and draws them at
+    /// once, so refreshing every output doesn't redraw the screen once per output.
+    async fn pick_fired(&mut self, output: String) {
+        // let the other timers of a refresh catch up
+        task::yield_now().await;
+        let mut outputs = vec![output];
+        while let Some(Some((output, ()))) = self.timers.timers.next().now_or_never() {
+            outputs.push(output);
+        }
+
+        for output in &outputs {
+            self.pick(output).await;
+        }
+        if let Err(e) = self.mon.commit().await {
+            tracing::error!("{}", e);
+        }
+    }
+
+    /// Starts timers for new outputs and stops the ones of outputs that went away.
+    async fn sync_outputs(&mut self) -> Result<(), Error> {
+        let outputs = self.mon.idents().await?;
+        let mut state = self.state.lock().await;
+        let state = match state.as_mut() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+
+        // timers are started over when the backend changes but the state is kept
+        let gone = state
+            .refresh_preempts
+            .keys()
+            .filter(|output| !outputs.contains(output))
+            .cloned()
+            .collect::<Vec<_>>();
+        for output in gone {
+            self.timers.stop(&output);
+            state.refresh_preempts.remove(&output);
+            state.current_wps.remove(&output);
+            state.history.remove(&output);
+        }
+
+        let mut cxn = self.pool.acquire().await?;
+        for output in outputs {
+            if self.timers.refresh(&output).is_none() {
+                let history = OutputHistory::load(&mut cxn, &output).await?;
+                state.history.insert(output.clone(), history);
+
+                let refresh = self
+                    .cfg
+                    .active(state.profile.as_deref(), &output, self.clock.now())
+                    .refresh;
+                let restored = match self.restore_wp(&mut cxn, state, &output, &refresh).await {
+                    Ok(restored) => restored,
+                    Err(e) => {
+                        tracing::error!(monitor = output.as_str(), "Can't restore wp: {}", e);
                         None
                     }
                 };
-                state.current_wps.insert(monitor, ent);
+                let mut preempt = self
+                    .timers
+                    .start(&output, refresh, self.clock.clone(), restored);
+                if restored.is_none() {
+                    preempt.try_preempt();
+                }
+                state.refresh_preempts.insert(output, preempt);
             }
         }
+        self.mon.commit().await?;
 
         Ok(())
     }
 
-    async fn pick(&mut self) {
-        if let Err(e) = self.pickwp().await {
-            tracing::error!("{}", e);
+    /// Shows the wallpaper `output` had before the daemon restarted unless it's gone or due to
+    /// be replaced anyway, pins stay pinned. Returns when it was set.
+    async fn restore_wp(
+        &mut self,
+        cxn: &mut sqlx::SqliteConnection,
+        state: &mut StateInner,
+        output: &str,
+        refresh: &Refresh,
+    ) -> Result<Option<OffsetDateTime>, Error> {
+        let saved = match db::fetch_output_wp(cxn, output).await? {
+            Some(saved)
+                if self
+                    .roots
+                    .iter()
+                    .any(|root| root.data.id() == saved.root_id) =>
+            {
+                saved
+            }
+            _ => return Ok(None),
+        };
+        if tokio::fs::metadata(&saved.path).await.is_err() {
+            return Ok(None);
+        }
+
+        let now = self.clock.now();
+        let set_at =
+            OffsetDateTime::from_unix_timestamp(saved.set_at.as_secs()).to_offset(now.offset());
+        // frozen and pinned wallpapers never get stale
+        let stale = refresh.until_next_since(set_at, now) == Some(Duration::from_secs(0));
+        if stale && !state.frozen && !saved.pinned {
+            return Ok(None);
+        }
+
+        let mode = self.cfg.active(state.profile.as_deref(), output, now).mode;
+        self.mon.set_wallpaper(mode, output, &saved.path).await?;
+        tracing::info!(monitor = output, path = saved.path.as_str(), "Restored wp");
+        let wp = CurrentWp {
+            id: saved.id,
+            path: saved.path,
+        };
+        if saved.pinned {
+            state.pinned.insert(output.to_owned(), wp.clone());
+        }
+        state.current_wps.insert(output.to_owned(), Some(wp));
+
+        Ok(Some(set_at))
+    }
+
+    async fn load_frozen(&self) -> Result<bool, Error> {
+        let mut cxn = self.pool.acquire().await?;
+        Ok(db::fetch_setting(&mut cxn, FROZEN_SETTING).await?.is_some())
+    }
+
+    async fn load_profile(&self) -> Result<Option<String>, Error> {
+        let mut cxn = self.pool.acquire().await?;
+        Ok(db::fetch_setting(&mut cxn, PROFILE_SETTING)
+            .await?
+            .filter(|name| {
+                let exists = self.cfg.profiles.contains_key(name);
+                if !exists {
+                    tracing::warn!("Profile {} doesn't exist anymore", name);
+                }
+                exists
+            }))
+    }
+
+    /// Fires when the active schedule entry might change next.
+    fn schedule_transition(&self) -> BoxFuture<'static, ()> {
+        schedule::next_transition(
+            &*self.clock,
+            self.cfg.schedule.iter().map(|schedule| &schedule.time),
+            self.cfg.location,
+        )
+    }
+
+    /// Refreshes every output if a different schedule entry applies now.
+    async fn switch_schedule(&mut self) {
+        let schedule = self.cfg.active_schedule(self.clock.now());
+        if schedule == self.schedule {
+            return;
+        }
+        self.schedule = schedule;
+        match schedule {
+            Some(i) => tracing::info!(schedule = i, "Switching to schedule"),
+            None => tracing::info!("Leaving schedule"),
+        }
+
+        if let Some(state) = self.state.lock().await.as_mut() {
+            // profiles take precedence so nothing changes
+            if state.profile.is_none() {
+                state.refresh();
+            }
+        }
+    }
+
+    /// Creates the state on startup and only updates what depends on the config on reload, so
+    /// current wallpapers, pins and the frozen state are kept.
+    async fn sync_state(&self) -> Result<(), Error> {
+        let roots = self
+            .roots
+            .iter()
+            .map(|root| {
+                root.scanner
+                    .handle(root.data.clone(), self.cfg.scan.clone())
+            })
+            .collect();
+        let profiles = self.cfg.profiles.keys().cloned().collect();
+
+        let mut state = self.state.lock().await;
+        match state.as_mut() {
+            Some(state) => {
+                state.scan_preempt = self.rescan_preempt.clone();
+                state.roots = roots;
+                state.profiles = profiles;
+                if let Some(name) = &state.profile {
+                    if !self.cfg.profiles.contains_key(name) {
+                        tracing::warn!("Profile {} doesn't exist anymore", name);
+                        state.profile = None;
+                    }
+                }
+            }
+            None => {
+                *state = Some(StateInner::new(
+                    self.rescan_preempt.clone(),
+                    roots,
+                    self.load_frozen().await?,
+                    self.load_profile().await?,
+                    profiles,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restarts the timers of outputs whose refresh settings changed.
+    async fn sync_timers(&mut self) {
+        let mut state = self.state.lock().await;
+        let state = match state.as_mut() {
+            Some(state) => state,
+            None => return,
+        };
+
+        let now = self.clock.now();
+        let outputs = self.timers.outputs().cloned().collect::<Vec<_>>();
+        for output in outputs {
+            let refresh = self
+                .cfg
+                .active(state.profile.as_deref(), &output, now)
+                .refresh;
+            if self.timers.refresh(&output) != Some(&refresh) {
+                let preempt = self
+                    .timers
+                    .start(&output, refresh, self.clock.clone(), None);
+                state.refresh_preempts.insert(output, preempt);
+            }
         }
     }
 
     async fn run(mut self) -> Result<LoopExit, Error> {
-        let (mut refresh_preempt, mut refresh) =
-            crate::util::preemptible_interval(self.cfg.refresh_interval);
-        let (mut rescan_preempt, mut rescan) =
-            crate::util::preemptible_interval(self.cfg.rescan_interval);
-        refresh_preempt.preempt().await;
-        rescan_preempt.preempt().await;
+        self.sync_state().await?;
+        self.sync_timers().await;
+
+        if let Err(e) = self.sync_outputs().await {
+            tracing::error!("{}", e);
+        }
 
-        self.state
-            .store(StateInner::new(refresh_preempt, rescan_preempt))
-            .await;
+        let mut transition = self.schedule_transition();
 
         loop {
             tokio::select! {
                 Some(new_cfg) = self.cfg_reload.next() => {
                     match Config::from_slice(&new_cfg) {
                         Ok(new_cfg) => {
+                            warn_unknown_tags(self.pool, &new_cfg).await;
                             tracing::info!("Reloaded config");
                             return Ok(LoopExit::NewCfg(new_cfg));
                         }
@@ -215,17 +882,39 @@ where
                     return Ok(LoopExit::Terminate);
                 }
 
-                Some(_) = rescan.next() => {
-                    self.image_scanner.start_scan(self.pool, self.root.clone());
+                Some(_) = self.rescan.next() => {
+                    for root in self.roots {
+                        root.scanner.start_scan(self.pool, root.data.clone(), self.cfg.scan.clone());
+                    }
                 }
 
-                Some(_) = refresh.next() => {
-                    self.pick().await;
+                Some(_) = self.retag.next() => {
+                    for root in self.roots {
+                        root.scanner
+                            .handle(root.data.clone(), self.cfg.scan.clone())
+                            .start_retag(self.pool);
+                    }
+                }
+
+                _ = &mut transition, if !self.cfg.schedule.is_empty() => {
+                    self.switch_schedule().await;
+                    transition = self.schedule_transition();
+                }
+
+                Some((output, ())) = self.timers.timers.next() => {
+                    self.pick_fired(output).await;
                 }
 
                 Some(event) = self.display_changed.next() => {
                     match event {
-                        Ok(()) => self.pick().await,
+                        Ok(()) => {
+                            if let Err(e) = self.sync_outputs().await {
+                                tracing::error!("{}", e);
+                            }
+                            if let Some(state) = self.state.lock().await.as_mut() {
+                                state.refresh();
+                            }
+                        }
                         Err(e) => {
                             tracing::error!("{}", e);
                         }
@@ -1,5 +1,11 @@
 use crate::db::RootId;
-use std::{convert::TryFrom, os::unix::prelude::*, path::PathBuf, time::SystemTime};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 use time::OffsetDateTime;
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, derive_more::Deref, derive_more::AsRef)]
@@ -33,10 +39,21 @@ impl TryFrom<String> for RelativePath {
     }
 }
 
-#[derive(Eq, PartialEq, Debug, sqlx::Type, Clone, Copy)]
+#[derive(Eq, PartialEq, Debug, sqlx::Type, Clone, Copy, Serialize, Deserialize)]
 #[sqlx(transparent)]
+#[serde(transparent)]
 pub struct UnixTimestamp(i64);
 
+impl UnixTimestamp {
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    pub fn as_secs(self) -> i64 {
+        self.0
+    }
+}
+
 impl From<i64> for UnixTimestamp {
     fn from(other: i64) -> Self {
         Self(other)
@@ -62,6 +79,80 @@ impl From<OffsetDateTime> for UnixTimestamp {
     }
 }
 
+#[derive(Debug, Clone, Hash, PartialEq, Eq, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct ContentHash(Vec<u8>);
+
+impl From<&tgcd::Blake2bHash> for ContentHash {
+    fn from(other: &tgcd::Blake2bHash) -> Self {
+        Self(other.as_ref().to_vec())
+    }
+}
+
+impl ContentHash {
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// `None` if the stored hash has the wrong length.
+    pub fn to_blake2b(&self) -> Option<tgcd::Blake2bHash> {
+        tgcd::Blake2bHash::try_from(self.0.as_slice()).ok()
+    }
+}
+
+/// Size of an image in pixels.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Dimensions {
+    /// Reads the dimensions from the image header without decoding the whole image.
+    pub fn from_file(path: impl AsRef<Path>) -> image::ImageResult<Self> {
+        let (width, height) = image::io::Reader::open(path)?
+            .with_guessed_format()?
+            .into_dimensions()?;
+        Ok(Self { width, height })
+    }
+}
+
+/// A star rating from 1 to 5.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "u8")]
+pub struct Rating(u8);
+
+#[derive(snafu::Snafu, Debug)]
+pub enum RatingError {
+    #[snafu(display("Rating must be between 1 and 5, got {}", rating))]
+    OutOfRange { rating: u8 },
+}
+
+impl TryFrom<u8> for Rating {
+    type Error = RatingError;
+    fn try_from(rating: u8) -> Result<Self, Self::Error> {
+        if (1..=5).contains(&rating) {
+            Ok(Self(rating))
+        } else {
+            Err(RatingError::OutOfRange { rating })
+        }
+    }
+}
+
+impl Rating {
+    pub fn stars(self) -> u8 {
+        self.0
+    }
+}
+
+/// What gets read from an image file besides its hash.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImageMeta {
+    pub dimensions: Option<Dimensions>,
+    /// Rating embedded in the file, replaces the stored one if present
+    pub rating: Option<Rating>,
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub struct Time {
     pub mtime: UnixTimestamp,
@@ -74,3 +165,24 @@ pub struct PathData {
     pub path: RelativePath,
     pub time: Time,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub output: String,
+    pub path: String,
+    pub timestamp: UnixTimestamp,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RetagStats {
+    pub files: u32,
+    pub added: u32,
+    pub removed: u32,
+}
+
+/// Files with identical content.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Duplicates {
+    pub hash: String,
+    pub paths: Vec<String>,
+}
@@ -1,14 +1,18 @@
 use crate::{
-    cfg::Filter,
-    data::{PathData, RelativePath, Time, UnixTimestamp},
+    cfg::{Filter, LastShown},
+    data::{
+        ContentHash, Dimensions, Duplicates, HistoryEntry, ImageMeta, PathData, Rating,
+        RelativePath, Time, UnixTimestamp,
+    },
 };
+use rand::distributions::{Distribution, WeightedIndex};
 use snafu::ResultExt;
 use sqlx::{prelude::*, sqlite::SqliteRow, SqliteConnection};
 use std::{
     convert::TryFrom,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
-use tgcd::Tag;
 
 #[derive(snafu::Snafu, Debug)]
 pub enum OpenError {
@@ -87,19 +91,37 @@ impl RootData {
     }
 }
 
-pub async fn update_timestamp(cxn: &mut SqliteConnection, data: &PathData) -> Result<(), Error> {
+pub async fn update_path(
+    cxn: &mut SqliteConnection,
+    data: &PathData,
+    hash: &ContentHash,
+    meta: ImageMeta,
+) -> Result<(), Error> {
     let path = data.path.as_ref();
+    let (width, height) = split_dimensions(meta.dimensions);
+    let rating = rating_column(meta.rating);
 
     sqlx::query!(
         "
         UPDATE relative_path
         SET unix_mtime = ?,
-            unix_btime = ?
+            unix_btime = ?,
+            hash = ?,
+            width = ?,
+            height = ?,
+            -- ratings set by the user win over embedded ones
+            rating = coalesce(rating, ?),
+            excluded = 0,
+            reindex = 0
         WHERE file_path = ? AND
               root_id = ?
         ",
         data.time.mtime,
         data.time.btime,
+        hash,
+        width,
+        height,
+        rating,
         path,
         data.root_id
     )
@@ -110,35 +132,116 @@ pub async fn update_timestamp(cxn: &mut SqliteConnection, data: &PathData) -> Re
     })
 }
 
-pub async fn fetch_path_time(
+fn split_dimensions(dimensions: Option<Dimensions>) -> (Option<i64>, Option<i64>) {
+    (
+        dimensions.map(|dim| i64::from(dim.width)),
+        dimensions.map(|dim| i64::from(dim.height)),
+    )
+}
+
+fn rating_column(rating: Option<Rating>) -> Option<i64> {
+    rating.map(|rating| i64::from(rating.stars()))
+}
+
+pub struct PathMeta {
+    pub time: Time,
+    /// Whether it was hashed and doesn't need to be indexed again
+    pub indexed: bool,
+}
+
+pub async fn fetch_path_meta(
     cxn: &mut SqliteConnection,
     root_id: RootId,
     path: &RelativePath,
-) -> Result<Option<Time>, Error> {
+) -> Result<Option<PathMeta>, Error> {
     sqlx::query(
-        "SELECT unix_mtime, unix_btime FROM relative_path WHERE file_path = ? AND root_id = ?",
+        "
+        SELECT unix_mtime, unix_btime, hash IS NOT NULL AND NOT reindex AS indexed
+        FROM relative_path
+        WHERE file_path = ? AND root_id = ?
+        ",
     )
     .bind(path.as_ref())
     .bind(root_id)
     .try_map(|row: sqlx::sqlite::SqliteRow| {
-        Ok(Time {
-            btime: row.get("unix_btime"),
-            mtime: row.get("unix_mtime"),
+        Ok(PathMeta {
+            time: Time {
+                btime: row.get("unix_btime"),
+                mtime: row.get("unix_mtime"),
+            },
+            indexed: row.get("indexed"),
         })
     })
     .fetch_optional(cxn)
     .await
 }
 
+pub async fn fetch_path_id(
+    cxn: &mut SqliteConnection,
+    root_id: RootId,
+    path: &RelativePath,
+) -> Result<Option<PathId>, Error> {
+    sqlx::query("SELECT id FROM relative_path WHERE root_id = ? AND file_path = ?")
+        .bind(root_id)
+        .bind(path.as_ref())
+        .try_map(|row: SqliteRow| Ok(PathId(row.get("id"))))
+        .fetch_optional(cxn)
+        .await
+}
+
+fn path_row(row: SqliteRow) -> Result<(PathId, RelativePath, Option<ContentHash>), Error> {
+    let path: String = row.get("file_path");
+    Ok((
+        PathId(row.get("id")),
+        RelativePath::try_from(path).unwrap(),
+        row.get("hash"),
+    ))
+}
+
+pub async fn fetch_root_paths(
+    cxn: &mut SqliteConnection,
+    root_id: RootId,
+) -> Result<Vec<(PathId, RelativePath, Option<ContentHash>)>, Error> {
+    sqlx::query("SELECT id, file_path, hash FROM relative_path WHERE root_id = ?")
+        .bind(root_id)
+        .try_map(path_row)
+        .fetch_all(cxn)
+        .await
+}
+
+/// Fetches `path` itself or everything below it if it's a directory.
+pub async fn fetch_paths_under(
+    cxn: &mut SqliteConnection,
+    root_id: RootId,
+    path: &RelativePath,
+) -> Result<Vec<(PathId, RelativePath, Option<ContentHash>)>, Error> {
+    sqlx::query(
+        "
+        SELECT id, file_path, hash
+        FROM relative_path
+        WHERE
+            root_id = ?1
+            AND (file_path = ?2 OR substr(file_path, 1, length(?2) + 1) = ?2 || '/')
+        ",
+    )
+    .bind(root_id)
+    .bind(path.as_ref())
+    .try_map(path_row)
+    .fetch_all(cxn)
+    .await
+}
+
 pub async fn insert_new_path(
     cxn: &mut SqliteConnection,
     path: &PathData,
-    tags: &[Tag],
+    hash: &ContentHash,
+    meta: ImageMeta,
+    tags: &[String],
 ) -> Result<(), Error> {
-    let path_id = insert_relative_path(cxn, path).await?;
+    let path_id = insert_relative_path(cxn, path, hash, meta).await?;
     let mut tag_ids = Vec::with_capacity(tags.len());
     for tag in tags {
-        tag_ids.push(get_or_insert_tag(cxn, tag.as_ref()).await?);
+        tag_ids.push(get_or_insert_tag(cxn, tag).await?);
     }
 
     associate_path_with_tags(cxn, path_id, &tag_ids).await?;
@@ -146,6 +249,222 @@ pub async fn insert_new_path(
     Ok(())
 }
 
+/// Points an existing entry to a new path, keeping its tags and history.
+pub async fn move_path(
+    cxn: &mut SqliteConnection,
+    id: PathId,
+    data: &PathData,
+    hash: &ContentHash,
+    meta: ImageMeta,
+) -> Result<(), Error> {
+    let path = data.path.as_ref();
+    let (width, height) = split_dimensions(meta.dimensions);
+    let rating = rating_column(meta.rating);
+    sqlx::query!(
+        "
+        UPDATE relative_path
+        SET file_path = ?,
+            unix_mtime = ?,
+            unix_btime = ?,
+            hash = ?,
+            width = ?,
+            height = ?,
+            rating = coalesce(rating, ?),
+            excluded = 0,
+            reindex = 0
+        WHERE id = ?
+        ",
+        path,
+        data.time.mtime,
+        data.time.btime,
+        hash,
+        width,
+        height,
+        rating,
+        id
+    )
+    .execute(cxn)
+    .await
+    .map(|_| ())
+}
+
+pub async fn delete_path(cxn: &mut SqliteConnection, id: PathId) -> Result<(), Error> {
+    sqlx::query!("DELETE FROM path_tag WHERE relative_path_id = ?", id)
+        .execute(&mut *cxn)
+        .await?;
+    sqlx::query!("DELETE FROM history WHERE relative_path_id = ?", id)
+        .execute(&mut *cxn)
+        .await?;
+    // bans and favorites by hash stay in case the file comes back
+    sqlx::query!("DELETE FROM banned WHERE relative_path_id = ?", id)
+        .execute(&mut *cxn)
+        .await?;
+    sqlx::query!("DELETE FROM favorite WHERE relative_path_id = ?", id)
+        .execute(&mut *cxn)
+        .await?;
+    sqlx::query!("DELETE FROM output_wp WHERE relative_path_id = ?", id)
+        .execute(&mut *cxn)
+        .await?;
+    sqlx::query!("DELETE FROM relative_path WHERE id = ?", id)
+        .execute(cxn)
+        .await
+        .map(|_| ())
+}
+
+/// Time of `path` when it was rejected.
+pub async fn fetch_rejected(
+    cxn: &mut SqliteConnection,
+    root_id: RootId,
+    path: &RelativePath,
+) -> Result<Option<Time>, Error> {
+    sqlx::query(
+        "
+        SELECT unix_mtime, unix_btime
+        FROM rejected_path
+        WHERE root_id = ? AND file_path = ?
+        ",
+    )
+    .bind(root_id)
+    .bind(path.as_ref())
+    .try_map(|row: SqliteRow| {
+        Ok(Time {
+            mtime: row.get("unix_mtime"),
+            btime: row.get("unix_btime"),
+        })
+    })
+    .fetch_optional(cxn)
+    .await
+}
+
+pub async fn fetch_rejected_paths(
+    cxn: &mut SqliteConnection,
+    root_id: RootId,
+) -> Result<Vec<RelativePath>, Error> {
+    sqlx::query("SELECT file_path FROM rejected_path WHERE root_id = ?")
+        .bind(root_id)
+        .try_map(|row: SqliteRow| {
+            let path: String = row.get("file_path");
+            Ok(RelativePath::try_from(path).unwrap())
+        })
+        .fetch_all(cxn)
+        .await
+}
+
+/// Paths of `root_id` that are excluded by the scan config.
+pub async fn fetch_excluded_paths(
+    cxn: &mut SqliteConnection,
+    root_id: RootId,
+) -> Result<Vec<RelativePath>, Error> {
+    sqlx::query("SELECT file_path FROM relative_path WHERE root_id = ? AND excluded")
+        .bind(root_id)
+        .try_map(|row: SqliteRow| {
+            let path: String = row.get("file_path");
+            Ok(RelativePath::try_from(path).unwrap())
+        })
+        .fetch_all(cxn)
+        .await
+}
+
+/// Excluded paths keep their tags and history but don't get picked.
+pub async fn set_excluded(
+    cxn: &mut SqliteConnection,
+    id: PathId,
+    excluded: bool,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE relative_path SET excluded = ? WHERE id = ?",
+        excluded,
+        id
+    )
+    .execute(cxn)
+    .await
+    .map(|_| ())
+}
+
+pub async fn insert_rejected(cxn: &mut SqliteConnection, data: &PathData) -> Result<(), Error> {
+    let path = data.path.as_ref();
+    sqlx::query!(
+        "
+        INSERT OR REPLACE INTO
+            rejected_path(root_id, file_path, unix_mtime, unix_btime)
+        VALUES
+            (?, ?, ?, ?)
+        ",
+        data.root_id,
+        path,
+        data.time.mtime,
+        data.time.btime
+    )
+    .execute(cxn)
+    .await
+    .map(|_| ())
+}
+
+/// Forgets about `path` or everything below it if it's a directory.
+pub async fn delete_rejected(
+    cxn: &mut SqliteConnection,
+    root_id: RootId,
+    path: &RelativePath,
+) -> Result<(), Error> {
+    sqlx::query(
+        "
+        DELETE FROM rejected_path
+        WHERE
+            root_id = ?1
+            AND (file_path = ?2 OR substr(file_path, 1, length(?2) + 1) = ?2 || '/')
+        ",
+    )
+    .bind(root_id)
+    .bind(path.as_ref())
+    .execute(cxn)
+    .await
+    .map(|_| ())
+}
+
+/// Replaces the tags of `id` with `tags`, returns how many were added and removed.
+pub async fn set_path_tags(
+    cxn: &mut SqliteConnection,
+    id: PathId,
+    tags: &[String],
+) -> Result<(u32, u32), Error> {
+    let current = sqlx::query!(
+        "
+        SELECT tag.id, tag.name
+        FROM path_tag
+        INNER JOIN tag ON tag.id = path_tag.tag_id
+        WHERE path_tag.relative_path_id = ?
+        ",
+        id
+    )
+    .fetch_all(&mut *cxn)
+    .await?;
+
+    let mut removed = 0;
+    for row in &current {
+        if !tags.contains(&row.name) {
+            let tag_id = TagId(row.id);
+            sqlx::query!(
+                "DELETE FROM path_tag WHERE relative_path_id = ? AND tag_id = ?",
+                id,
+                tag_id
+            )
+            .execute(&mut *cxn)
+            .await?;
+            removed += 1;
+        }
+    }
+
+    let mut added = Vec::new();
+    for tag in tags {
+        if !current.iter().any(|row| &row.name == tag) {
+            added.push(get_or_insert_tag(cxn, tag).await?);
+        }
+    }
+    associate_path_with_tags(cxn, id, &added).await?;
+
+    Ok((added.len() as u32, removed))
+}
+
 async fn associate_path_with_tags(
     cxn: &mut SqliteConnection,
     path: PathId,
@@ -176,52 +495,54 @@ async fn get_or_insert_tag(cxn: &mut SqliteConnection, tag: &str) -> Result<TagI
 async fn insert_relative_path(
     cxn: &mut SqliteConnection,
     relative_path: &PathData,
+    hash: &ContentHash,
+    meta: ImageMeta,
 ) -> Result<PathId, Error> {
     let path = relative_path.path.as_ref();
+    let (width, height) = split_dimensions(meta.dimensions);
+    let rating = rating_column(meta.rating);
     sqlx::query!(
         "
         INSERT INTO
-            relative_path(root_id, file_path, unix_mtime, unix_btime)
+            relative_path(root_id, file_path, unix_mtime, unix_btime, hash, width, height, rating)
         VALUES
-            (?, ?, ?, ?)",
+            (?, ?, ?, ?, ?, ?, ?, ?)",
         relative_path.root_id,
         path,
         relative_path.time.mtime,
         relative_path.time.btime,
+        hash,
+        width,
+        height,
+        rating,
     )
     .execute(&mut *cxn)
     .await?;
 
-    sqlx::query!("SELECT id FROM relative_path WHERE file_path = ?", path,)
-        .fetch_one(cxn)
-        .await
-        .map(|row| PathId(row.id))
-}
-
-async fn fetch_tag_id(cxn: &mut SqliteConnection, tag: &str) -> Result<Option<i64>, Error> {
-    sqlx::query!("SELECT id FROM tag WHERE name = ?", tag)
-        .fetch_optional(cxn)
-        .await
-        .map(|row| row.map(|row| row.id))
+    sqlx::query!(
+        "SELECT id FROM relative_path WHERE file_path = ? AND root_id = ?",
+        path,
+        relative_path.root_id
+    )
+    .fetch_one(cxn)
+    .await
+    .map(|row| PathId(row.id))
 }
 
-async fn build_tag_where_clause(
-    cxn: &mut SqliteConnection,
-    tags: &[String],
-) -> Result<String, Error> {
-    let mut tag_ids = Vec::new();
+/// Returns every tag in `tags` that isn't in the database.
+pub async fn unknown_tags(cxn: &mut SqliteConnection, tags: &[&str]) -> Result<Vec<String>, Error> {
+    let mut ret = Vec::new();
     for tag in tags {
-        if let Some(id) = fetch_tag_id(cxn, tag).await? {
-            tag_ids.push(format!("{}", id));
+        let tag = *tag;
+        let known = sqlx::query!("SELECT id FROM tag WHERE name = ?", tag)
+            .fetch_optional(&mut *cxn)
+            .await?
+            .is_some();
+        if !known {
+            ret.push(tag.to_owned());
         }
     }
-
-    Ok(if tag_ids.is_empty() {
-        "AND 0 = 1".to_string()
-    } else {
-        let id_list = tag_ids.join(",");
-        format!("AND path_tag.tag_id IN ({})", id_list)
-    })
+    Ok(ret)
 }
 
 pub async fn get_or_insert_root(
@@ -242,10 +563,125 @@ pub async fn get_or_insert_root(
         })
 }
 
+/// How much the aspect ratio of an image may differ from the requested one, relative to it.
+const ASPECT_RATIO_TOLERANCE: f64 = 0.05;
+
+/// Picks a random wallpaper matching `filter`.
+/// `aspect_ratio` is the resolved aspect ratio of `filter`, if any.
+/// Files that also exist in one of the `preferred` roots belong to that root and are skipped,
+/// so identical files in several roots don't get picked more often.
 pub async fn pickwp(
     cxn: &mut SqliteConnection,
     root_id: RootId,
+    preferred: &[RootId],
     filter: &Filter,
+    aspect_ratio: Option<f64>,
+    exclude: &[PathId],
+) -> Result<Option<(PathId, RelativePath)>, Error> {
+    let last_shown = filter.last_shown;
+    if let Some(ret) = pick_one(
+        cxn,
+        root_id,
+        preferred,
+        filter,
+        aspect_ratio,
+        last_shown,
+        exclude,
+    )
+    .await?
+    {
+        return Ok(Some(ret));
+    }
+
+    if filter.last_shown != LastShown::Off {
+        tracing::debug!("Every matching wallpaper was shown recently, ignoring last-shown");
+        if let Some(ret) = pick_one(
+            cxn,
+            root_id,
+            preferred,
+            filter,
+            aspect_ratio,
+            LastShown::Off,
+            exclude,
+        )
+        .await?
+        {
+            return Ok(Some(ret));
+        }
+    }
+
+    if !exclude.is_empty() {
+        tracing::debug!("Not enough wallpapers for every output, allowing duplicates");
+        return pick_one(
+            cxn,
+            root_id,
+            preferred,
+            filter,
+            aspect_ratio,
+            LastShown::Off,
+            &[],
+        )
+        .await;
+    }
+
+    Ok(None)
+}
+
+/// Excludes the paths in `ids` and every file with the same content.
+/// `ids` gets used twice so its binds need to be bound twice.
+fn not_duplicate_of(ids: &str) -> String {
+    format!(
+        "
+            AND relative_path.id NOT IN ({ids})
+            AND (
+                relative_path.hash IS NULL
+                OR relative_path.hash NOT IN (
+                    SELECT hash FROM relative_path WHERE hash IS NOT NULL AND id IN ({ids})
+                )
+            )
+        ",
+        ids = ids
+    )
+}
+
+/// Joins the path of a root and a file in it as read from the database.
+fn join_path(root_path: &str, file_path: &str) -> Result<String, Error> {
+    Path::new(root_path)
+        .join(file_path)
+        .into_os_string()
+        .into_string()
+        .map_err(|path| Error::Decode(format!("Path is not valid UTF-8: {:?}", path).into()))
+}
+
+/// A value for a `?` in a query that is built at runtime.
+enum Bind<'a> {
+    Int(i64),
+    Real(f64),
+    Text(&'a str),
+}
+
+fn bind_all<'q>(
+    mut query: sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>>,
+    binds: &[Bind<'q>],
+) -> sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>> {
+    for bind in binds {
+        query = match *bind {
+            Bind::Int(n) => query.bind(n),
+            Bind::Real(x) => query.bind(x),
+            Bind::Text(s) => query.bind(s),
+        };
+    }
+    query
+}
+
+async fn pick_one(
+    cxn: &mut SqliteConnection,
+    root_id: RootId,
+    preferred: &[RootId],
+    filter: &Filter,
+    aspect_ratio: Option<f64>,
+    last_shown: LastShown,
+    exclude: &[PathId],
 ) -> Result<Option<(PathId, RelativePath)>, Error> {
     let to_time = filter
         .to_time
@@ -255,33 +691,193 @@ pub async fn pickwp(
         .from_time
         .map(UnixTimestamp::from)
         .unwrap_or(UnixTimestamp::from(std::i64::MIN));
+    let mut binds = vec![
+        Bind::Int(root_id.0),
+        Bind::Int(to_time.as_secs()),
+        Bind::Int(from_time.as_secs()),
+    ];
+
+    let mut dimension_clause = String::new();
+    if let Some(min_width) = filter.min_width {
+        dimension_clause.push_str(" AND relative_path.width >= ?");
+        binds.push(Bind::Int(i64::from(min_width)));
+    }
+    if let Some(min_height) = filter.min_height {
+        dimension_clause.push_str(" AND relative_path.height >= ?");
+        binds.push(Bind::Int(i64::from(min_height)));
+    }
+    if let Some(ratio) = aspect_ratio {
+        // division by zero is NULL in sqlite so broken images don't match
+        dimension_clause.push_str(
+            " AND abs(CAST(relative_path.width AS REAL) / relative_path.height - ?) <= ?",
+        );
+        binds.push(Bind::Real(ratio));
+        binds.push(Bind::Real(ratio * ASPECT_RATIO_TOLERANCE));
+    }
+
+    let rating_clause = match filter.min_rating {
+        Some(min_rating) => {
+            binds.push(Bind::Int(i64::from(min_rating.stars())));
+            " AND relative_path.rating >= ?"
+        }
+        None => "",
+    };
+
+    let (tag_clause, tag_binds) = match &filter.tags {
+        Some(expr) => expr.to_sql(),
+        None => ("1".to_string(), Vec::new()),
+    };
+    binds.extend(tag_binds.into_iter().map(Bind::Text));
+
+    let last_shown_ids = match last_shown {
+        LastShown::Off => None,
+        LastShown::Current => Some(
+            "SELECT relative_path_id
+             FROM history
+             WHERE id IN (SELECT MAX(id) FROM history GROUP BY output)"
+                .to_string(),
+        ),
+        LastShown::Picks(_) => {
+            Some("SELECT relative_path_id FROM history ORDER BY id DESC LIMIT ?".to_string())
+        }
+        LastShown::Within(_) => {
+            Some("SELECT relative_path_id FROM history WHERE unix_timestamp >= ?".to_string())
+        }
+    };
+    let last_shown_clause = last_shown_ids
+        .as_deref()
+        .map(not_duplicate_of)
+        .unwrap_or_default();
+    // every bind is needed twice, see `not_duplicate_of`
+    for _ in 0..2 {
+        match last_shown {
+            LastShown::Picks(n) => {
+                binds.push(Bind::Int(i64::from(n)));
+            }
+            LastShown::Within(timespan) => {
+                let since = SystemTime::now()
+                    .checked_sub(timespan)
+                    .map(UnixTimestamp::from)
+                    .unwrap_or(UnixTimestamp::from(std::i64::MIN));
+                binds.push(Bind::Int(since.as_secs()));
+            }
+            LastShown::Off | LastShown::Current => (),
+        }
+    }
+
+    let exclude_clause = if exclude.is_empty() {
+        String::new()
+    } else {
+        not_duplicate_of(&vec!["?"; exclude.len()].join(","))
+    };
+    for _ in 0..2 {
+        binds.extend(exclude.iter().map(|id| Bind::Int(id.0)));
+    }
 
-    let query = format!(
+    let banned_clause = "
+        AND NOT EXISTS (
+            SELECT 1 FROM banned
+            WHERE banned.hash = relative_path.hash OR banned.relative_path_id = relative_path.id
+        )
+    ";
+
+    let preferred_clause = if preferred.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "
+                AND (
+                    relative_path.hash IS NULL
+                    OR NOT EXISTS (
+                        SELECT 1 FROM relative_path AS other
+                        WHERE
+                            other.hash = relative_path.hash
+                            AND NOT other.excluded
+                            AND other.root_id IN ({})
+                    )
+                )
+            ",
+            vec!["?"; preferred.len()].join(",")
+        )
+    };
+    binds.extend(preferred.iter().map(|id| Bind::Int(id.0)));
+
+    let candidates = format!(
         "
             SELECT relative_path.id,
                    relative_path.file_path,
-                   (SELECT COUNT(*)
-                         FROM path_tag
-                         WHERE path_tag.relative_path_id = relative_path.id
-                               {}) AS tag_count
+                   MAX(EXISTS (
+                       SELECT 1 FROM favorite
+                       WHERE
+                           favorite.hash = relative_path.hash
+                           OR favorite.relative_path_id = relative_path.id
+                   )) AS favorite,
+                   MAX(relative_path.rating) AS rating
             FROM relative_path
             WHERE
                 root_id = ?
+                AND NOT relative_path.excluded
                 AND relative_path.unix_mtime <= ?
                 AND relative_path.unix_mtime >= ?
-                AND tag_count = ?
-            ORDER BY RANDOM()
-            LIMIT 1
+                {}
+                {}
+                AND ({})
+                {}
+                {}
+                {}
+                {}
+            -- identical files only count once
+            GROUP BY coalesce(relative_path.hash, relative_path.id)
         ",
-        // NOTE: this builds a list of numbers, sql injection not possible
-        build_tag_where_clause(cxn, &filter.tags).await?
+        dimension_clause,
+        rating_clause,
+        tag_clause,
+        last_shown_clause,
+        exclude_clause,
+        banned_clause,
+        preferred_clause,
     );
 
-    sqlx::query(&query)
-        .bind(root_id)
-        .bind(to_time)
-        .bind(from_time)
-        .bind(i32::try_from(filter.tags.len()).unwrap())
+    // every wallpaper in a class of favorite and rating has the same weight, so pick a class
+    // by its total weight first and then a random wallpaper in it
+    let count_query = format!(
+        "SELECT favorite, rating, COUNT(*) AS count FROM ({}) GROUP BY favorite, rating",
+        candidates
+    );
+    let counts = bind_all(sqlx::query(&count_query), &binds)
+        .try_map(|row: SqliteRow| {
+            let favorite: i64 = row.get("favorite");
+            let rating: Option<i64> = row.get("rating");
+            let count: i64 = row.get("count");
+            Ok((favorite, rating, count))
+        })
+        .fetch_all(&mut *cxn)
+        .await?;
+
+    let weights = counts.iter().map(|&(favorite, rating, count)| {
+        let rating = rating
+            .and_then(|rating| u8::try_from(rating).ok())
+            .and_then(|rating| Rating::try_from(rating).ok());
+        count as f64 * filter.weight(favorite != 0, rating)
+    });
+    let (favorite, rating, _) = match WeightedIndex::new(weights) {
+        Ok(weights) => counts[weights.sample(&mut rand::thread_rng())],
+        // nothing matches or only wallpapers with a weight of 0
+        Err(_) => return Ok(None),
+    };
+
+    let pick_query = format!(
+        "
+            SELECT id, file_path FROM ({})
+            WHERE favorite = ? AND rating IS ?
+            ORDER BY random()
+            LIMIT 1
+        ",
+        candidates
+    );
+    bind_all(sqlx::query(&pick_query), &binds)
+        .bind(favorite)
+        .bind(rating)
         .try_map(|row: SqliteRow| {
             let path: String = row.get("file_path");
             Ok((PathId(row.get("id")), RelativePath::try_from(path).unwrap()))
@@ -289,3 +885,325 @@ pub async fn pickwp(
         .fetch_optional(cxn)
         .await
 }
+
+/// Bans the content of `id` so copies and moved files stay banned, or just the path if it
+/// wasn't hashed yet.
+pub async fn set_banned(cxn: &mut SqliteConnection, id: PathId, banned: bool) -> Result<(), Error> {
+    if banned {
+        sqlx::query!(
+            "
+            INSERT OR IGNORE INTO banned(hash, relative_path_id)
+            SELECT hash, CASE WHEN hash IS NULL THEN id END
+            FROM relative_path
+            WHERE id = ?
+            ",
+            id
+        )
+        .execute(cxn)
+        .await
+        .map(|_| ())
+    } else {
+        sqlx::query!(
+            "
+            DELETE FROM banned
+            WHERE
+                relative_path_id = ?
+                OR hash = (SELECT hash FROM relative_path WHERE id = ?)
+            ",
+            id,
+            id
+        )
+        .execute(cxn)
+        .await
+        .map(|_| ())
+    }
+}
+
+pub async fn set_rating(
+    cxn: &mut SqliteConnection,
+    id: PathId,
+    rating: Option<Rating>,
+) -> Result<(), Error> {
+    let rating = rating_column(rating);
+    sqlx::query!(
+        "UPDATE relative_path SET rating = ? WHERE id = ?",
+        rating,
+        id
+    )
+    .execute(cxn)
+    .await
+    .map(|_| ())
+}
+
+/// Like [`set_banned`] for favorites.
+pub async fn set_favorite(
+    cxn: &mut SqliteConnection,
+    id: PathId,
+    favorite: bool,
+) -> Result<(), Error> {
+    if favorite {
+        sqlx::query!(
+            "
+            INSERT OR IGNORE INTO favorite(hash, relative_path_id)
+            SELECT hash, CASE WHEN hash IS NULL THEN id END
+            FROM relative_path
+            WHERE id = ?
+            ",
+            id
+        )
+        .execute(cxn)
+        .await
+        .map(|_| ())
+    } else {
+        sqlx::query!(
+            "
+            DELETE FROM favorite
+            WHERE
+                relative_path_id = ?
+                OR hash = (SELECT hash FROM relative_path WHERE id = ?)
+            ",
+            id,
+            id
+        )
+        .execute(cxn)
+        .await
+        .map(|_| ())
+    }
+}
+
+pub async fn insert_history(
+    cxn: &mut SqliteConnection,
+    path_id: PathId,
+    output: &str,
+    time: UnixTimestamp,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO history(unix_timestamp, relative_path_id, output) VALUES (?, ?, ?)",
+        time,
+        path_id,
+        output
+    )
+    .execute(cxn)
+    .await
+    .map(|_| ())
+}
+
+pub async fn fetch_history(
+    cxn: &mut SqliteConnection,
+    output: Option<&str>,
+    since: UnixTimestamp,
+    limit: Option<u32>,
+) -> Result<Vec<HistoryEntry>, Error> {
+    sqlx::query(
+        "
+        SELECT history.unix_timestamp,
+               history.output,
+               root.root_path,
+               relative_path.file_path
+        FROM history
+        INNER JOIN relative_path ON relative_path.id = history.relative_path_id
+        INNER JOIN root ON root.id = relative_path.root_id
+        WHERE
+            (?1 IS NULL OR history.output = ?1)
+            AND history.unix_timestamp >= ?2
+        ORDER BY history.id DESC
+        LIMIT ?3
+        ",
+    )
+    .bind(output)
+    .bind(since)
+    // NOTE: negative LIMIT means no limit in sqlite
+    .bind(limit.map(i64::from).unwrap_or(-1))
+    .try_map(|row: SqliteRow| {
+        let root_path: String = row.get("root_path");
+        let file_path: String = row.get("file_path");
+        Ok(HistoryEntry {
+            output: row.get("output"),
+            path: join_path(&root_path, &file_path)?,
+            timestamp: row.get("unix_timestamp"),
+        })
+    })
+    .fetch_all(cxn)
+    .await
+}
+
+/// The last `limit` wallpapers shown on `output` as absolute paths, newest first.
+pub async fn fetch_output_history(
+    cxn: &mut SqliteConnection,
+    output: &str,
+    limit: u32,
+) -> Result<Vec<(PathId, String)>, Error> {
+    sqlx::query(
+        "
+        SELECT relative_path.id,
+               root.root_path,
+               relative_path.file_path
+        FROM history
+        INNER JOIN relative_path ON relative_path.id = history.relative_path_id
+        INNER JOIN root ON root.id = relative_path.root_id
+        WHERE history.output = ?
+        ORDER BY history.id DESC
+        LIMIT ?
+        ",
+    )
+    .bind(output)
+    .bind(i64::from(limit))
+    .try_map(|row: SqliteRow| {
+        let root_path: String = row.get("root_path");
+        let file_path: String = row.get("file_path");
+        Ok((PathId(row.get("id")), join_path(&root_path, &file_path)?))
+    })
+    .fetch_all(cxn)
+    .await
+}
+
+/// The wallpaper an output showed last.
+pub struct OutputWp {
+    pub id: PathId,
+    pub root_id: RootId,
+    pub path: String,
+    pub set_at: UnixTimestamp,
+    pub pinned: bool,
+}
+
+pub async fn fetch_output_wp(
+    cxn: &mut SqliteConnection,
+    output: &str,
+) -> Result<Option<OutputWp>, Error> {
+    sqlx::query(
+        "
+        SELECT relative_path.id,
+               relative_path.root_id,
+               root.root_path,
+               relative_path.file_path,
+               output_wp.unix_timestamp,
+               output_wp.pinned
+        FROM output_wp
+        INNER JOIN relative_path ON relative_path.id = output_wp.relative_path_id
+        INNER JOIN root ON root.id = relative_path.root_id
+        WHERE output_wp.output = ?
+        ",
+    )
+    .bind(output)
+    .try_map(|row: SqliteRow| {
+        let root_path: String = row.get("root_path");
+        let file_path: String = row.get("file_path");
+        Ok(OutputWp {
+            id: PathId(row.get("id")),
+            root_id: RootId(row.get("root_id")),
+            path: join_path(&root_path, &file_path)?,
+            set_at: row.get("unix_timestamp"),
+            pinned: row.get("pinned"),
+        })
+    })
+    .fetch_optional(cxn)
+    .await
+}
+
+pub async fn store_output_wp(
+    cxn: &mut SqliteConnection,
+    output: &str,
+    path_id: PathId,
+    time: UnixTimestamp,
+    pinned: bool,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "
+        INSERT OR REPLACE INTO
+            output_wp(output, relative_path_id, unix_timestamp, pinned)
+        VALUES
+            (?, ?, ?, ?)
+        ",
+        output,
+        path_id,
+        time,
+        pinned
+    )
+    .execute(cxn)
+    .await
+    .map(|_| ())
+}
+
+/// Keeps the wallpaper of `output` around for restoring but lets it change again.
+pub async fn unpin_output_wp(cxn: &mut SqliteConnection, output: &str) -> Result<(), Error> {
+    sqlx::query!("UPDATE output_wp SET pinned = 0 WHERE output = ?", output)
+        .execute(cxn)
+        .await
+        .map(|_| ())
+}
+
+/// Every group of files with the same content over all roots.
+pub async fn fetch_duplicates(cxn: &mut SqliteConnection) -> Result<Vec<Duplicates>, Error> {
+    let rows = sqlx::query(
+        "
+        SELECT relative_path.hash,
+               root.root_path,
+               relative_path.file_path
+        FROM relative_path
+        INNER JOIN root ON root.id = relative_path.root_id
+        WHERE relative_path.hash IN (
+            SELECT hash
+            FROM relative_path
+            WHERE hash IS NOT NULL
+            GROUP BY hash
+            HAVING COUNT(*) > 1
+        )
+        ORDER BY relative_path.hash, root.root_path, relative_path.file_path
+        ",
+    )
+    .try_map(|row: SqliteRow| {
+        let hash: ContentHash = row.get("hash");
+        let root_path: String = row.get("root_path");
+        let file_path: String = row.get("file_path");
+        Ok((hash, join_path(&root_path, &file_path)?))
+    })
+    .fetch_all(cxn)
+    .await?;
+
+    let mut ret: Vec<(ContentHash, Vec<String>)> = Vec::new();
+    for (hash, path) in rows {
+        match ret.last_mut() {
+            Some((last, paths)) if *last == hash => paths.push(path),
+            _ => ret.push((hash, vec![path])),
+        }
+    }
+
+    Ok(ret
+        .into_iter()
+        .map(|(hash, paths)| Duplicates {
+            hash: hash.to_hex(),
+            paths,
+        })
+        .collect())
+}
+
+pub async fn fetch_setting(
+    cxn: &mut SqliteConnection,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    sqlx::query!("SELECT value FROM setting WHERE name = ?", name)
+        .fetch_optional(cxn)
+        .await
+        .map(|row| row.map(|row| row.value))
+}
+
+pub async fn store_setting(
+    cxn: &mut SqliteConnection,
+    name: &str,
+    value: Option<&str>,
+) -> Result<(), Error> {
+    match value {
+        Some(value) => sqlx::query!(
+            "INSERT OR REPLACE INTO setting(name, value) VALUES (?, ?)",
+            name,
+            value
+        )
+        .execute(cxn)
+        .await
+        .map(|_| ()),
+        None => sqlx::query!("DELETE FROM setting WHERE name = ?", name)
+            .execute(cxn)
+            .await
+            .map(|_| ()),
+    }
+}
@@ -0,0 +1,420 @@
+//! Metadata from XMP packets and IPTC records embedded in image files.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Bigger chunks get skipped, nobody embeds that much metadata.
+const MAX_CHUNK_SIZE: u64 = 16 << 20;
+
+/// The parts of an image file that metadata is embedded in.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    xmp: Vec<u8>,
+    /// IPTC IIM records
+    iptc: Vec<u8>,
+}
+
+impl Metadata {
+    /// Reads just the metadata of a JPEG, PNG or WebP file and skips over the image data.
+    /// Other formats have none.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    fn from_reader(mut reader: impl Read + Seek) -> io::Result<Self> {
+        let mut magic = [0; 12];
+        let mut ret = Self::default();
+        let res = reader.read_exact(&mut magic).and_then(|()| {
+            if magic.starts_with(&[0xff, 0xd8]) {
+                ret.read_jpeg(&mut reader)
+            } else if magic.starts_with(b"\x89PNG\r\n\x1a\n") {
+                ret.read_png(&mut reader)
+            } else if magic.starts_with(b"RIFF") && &magic[8..] == b"WEBP" {
+                ret.read_webp(&mut reader)
+            } else {
+                Ok(())
+            }
+        });
+
+        match res {
+            Ok(()) => Ok(ret),
+            // truncated files keep whatever was found before the end
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(ret),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Walks the segments before the image data.
+    fn read_jpeg(&mut self, reader: &mut (impl Read + Seek)) -> io::Result<()> {
+        const APP1: u8 = 0xe1;
+        const APP13: u8 = 0xed;
+        const SOS: u8 = 0xda;
+        const EOI: u8 = 0xd9;
+        const XMP: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+        const PHOTOSHOP: &[u8] = b"Photoshop 3.0\0";
+
+        reader.seek(SeekFrom::Start(2))?;
+        loop {
+            let mut header = [0; 4];
+            reader.read_exact(&mut header)?;
+            let marker = header[1];
+            // image data follows, no more metadata
+            if header[0] != 0xff || marker == SOS || marker == EOI {
+                return Ok(());
+            }
+            // the length includes its own two bytes
+            let len = u64::from(u16::from_be_bytes([header[2], header[3]])).saturating_sub(2);
+            match marker {
+                APP1 | APP13 => {
+                    let data = read_chunk(reader, len)?;
+                    if marker == APP1 && data.starts_with(XMP) {
+                        self.xmp = data[XMP.len()..].to_vec();
+                    } else if marker == APP13 && data.starts_with(PHOTOSHOP) {
+                        if let Some(iptc) = photoshop_iptc(&data[PHOTOSHOP.len()..]) {
+                            self.iptc = iptc.to_vec();
+                        }
+                    }
+                }
+                _ => {
+                    reader.seek(SeekFrom::Current(len as i64))?;
+                }
+            }
+        }
+    }
+
+    /// Looks for an uncompressed `iTXt` chunk with the XMP keyword, it can be anywhere.
+    fn read_png(&mut self, reader: &mut (impl Read + Seek)) -> io::Result<()> {
+        const XMP: &[u8] = b"XML:com.adobe.xmp\0";
+        const CRC_LEN: i64 = 4;
+
+        reader.seek(SeekFrom::Start(8))?;
+        loop {
+            let mut header = [0; 8];
+            reader.read_exact(&mut header)?;
+            let len = u64::from(u32::from_be_bytes([
+                header[0], header[1], header[2], header[3],
+            ]));
+            match &header[4..] {
+                b"IEND" => return Ok(()),
+                b"iTXt" if len <= MAX_CHUNK_SIZE => {
+                    let data = read_chunk(reader, len)?;
+                    if data.starts_with(XMP) {
+                        if let Some(xmp) = itxt_text(&data[XMP.len()..]) {
+                            self.xmp = xmp.to_vec();
+                        }
+                    }
+                    reader.seek(SeekFrom::Current(CRC_LEN))?;
+                }
+                _ => {
+                    reader.seek(SeekFrom::Current(len as i64 + CRC_LEN))?;
+                }
+            }
+        }
+    }
+
+    /// Looks for the `XMP ` chunk of the RIFF container.
+    fn read_webp(&mut self, reader: &mut (impl Read + Seek)) -> io::Result<()> {
+        loop {
+            let mut header = [0; 8];
+            reader.read_exact(&mut header)?;
+            let len = u64::from(u32::from_le_bytes([
+                header[4], header[5], header[6], header[7],
+            ]));
+            if &header[..4] == b"XMP " && len <= MAX_CHUNK_SIZE {
+                self.xmp = read_chunk(reader, len)?;
+                return Ok(());
+            }
+            // chunks are padded to an even size
+            reader.seek(SeekFrom::Current((len + (len & 1)) as i64))?;
+        }
+    }
+
+    /// XMP `dc:subject` and IPTC keywords.
+    pub fn keywords(&self) -> Vec<String> {
+        let mut ret = xmp_subjects(&self.xmp);
+        ret.extend(iim_keywords(&self.iptc));
+        ret
+    }
+
+    /// `xmp:Rating`, `None` if it's unrated or rejected.
+    pub fn rating(&self) -> Option<u8> {
+        xmp_rating(&self.xmp)
+    }
+}
+
+fn read_chunk(reader: &mut impl Read, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+/// Text of an `iTXt` chunk after the keyword, `None` if it's compressed.
+fn itxt_text(buf: &[u8]) -> Option<&[u8]> {
+    // compression flag and method
+    if *buf.first()? != 0 {
+        return None;
+    }
+    let rest = buf.get(2..)?;
+    // language tag and translated keyword
+    let lang_end = rest.iter().position(|&b| b == 0)?;
+    let rest = &rest[lang_end + 1..];
+    let keyword_end = rest.iter().position(|&b| b == 0)?;
+    Some(&rest[keyword_end + 1..])
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Content between `start` and `end` after the first occurence of `start`.
+fn between<'a>(buf: &'a [u8], start: &[u8], end: &[u8]) -> Option<&'a [u8]> {
+    let begin = find(buf, start)? + start.len();
+    let len = find(&buf[begin..], end)?;
+    Some(&buf[begin..begin + len])
+}
+
+// This isn't a full XML parser but good enough for what image editors write.
+fn xmp_subjects(buf: &[u8]) -> Vec<String> {
+    let packet = match between(buf, b"<x:xmpmeta", b"</x:xmpmeta>") {
+        Some(packet) => packet,
+        None => return Vec::new(),
+    };
+    let mut subject = match between(packet, b"<dc:subject>", b"</dc:subject>") {
+        Some(subject) => subject,
+        None => return Vec::new(),
+    };
+
+    let mut ret = Vec::new();
+    while let Some(start) = find(subject, b"<rdf:li") {
+        let rest = &subject[start..];
+        let (content_start, content_len) = match (find(rest, b">"), find(rest, b"</rdf:li>")) {
+            (Some(open_end), Some(close)) if open_end < close => {
+                (open_end + 1, close - open_end - 1)
+            }
+            _ => break,
+        };
+        let keyword = unescape_xml(&String::from_utf8_lossy(
+            &rest[content_start..content_start + content_len],
+        ));
+        let keyword = keyword.trim();
+        if !keyword.is_empty() {
+            ret.push(keyword.to_owned());
+        }
+        subject = &rest[content_start + content_len..];
+    }
+
+    ret
+}
+
+fn xmp_rating(buf: &[u8]) -> Option<u8> {
+    let packet = between(buf, b"<x:xmpmeta", b"</x:xmpmeta>")?;
+    // either an attribute of rdf:Description or an element
+    let value = between(packet, b"xmp:Rating=\"", b"\"")
+        .or_else(|| between(packet, b"xmp:Rating='", b"'"))
+        .or_else(|| between(packet, b"<xmp:Rating>", b"</xmp:Rating>"))?;
+    // 0 is unrated and -1 rejected
+    match std::str::from_utf8(value)
+        .ok()?
+        .trim()
+        .parse::<f64>()
+        .ok()?
+    {
+        rating if (1. ..=5.).contains(&rating) => Some(rating.round() as u8),
+        _ => None,
+    }
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+const IPTC_KEYWORDS: (u8, u8) = (2, 25);
+const PHOTOSHOP_IPTC_RESOURCE: u16 = 0x0404;
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    buf.get(pos..pos + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    buf.get(pos..pos + 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn photoshop_iptc(mut buf: &[u8]) -> Option<&[u8]> {
+    while buf.starts_with(b"8BIM") {
+        let id = read_u16(buf, 4)?;
+        // pascal string padded to an even length including the length byte
+        let name_len = usize::from(*buf.get(6)?);
+        let name_len = (name_len + 1 + 1) & !1;
+        let size_pos = 6 + name_len;
+        let size = read_u32(buf, size_pos)? as usize;
+        let data_pos = size_pos + 4;
+        let data = buf.get(data_pos..data_pos + size)?;
+        if id == PHOTOSHOP_IPTC_RESOURCE {
+            return Some(data);
+        }
+        buf = buf.get(data_pos + ((size + 1) & !1)..)?;
+    }
+
+    None
+}
+
+fn iim_keywords(mut buf: &[u8]) -> Vec<String> {
+    let mut ret = Vec::new();
+    while buf.len() >= 5 && buf[0] == 0x1c {
+        let (record, dataset) = (buf[1], buf[2]);
+        let size = match read_u16(buf, 3) {
+            // extended datasets are never keywords
+            Some(size) if size & 0x8000 == 0 => usize::from(size),
+            _ => break,
+        };
+        let data = match buf.get(5..5 + size) {
+            Some(data) => data,
+            None => break,
+        };
+        if (record, dataset) == IPTC_KEYWORDS {
+            let keyword = String::from_utf8_lossy(data);
+            let keyword = keyword.trim();
+            if !keyword.is_empty() {
+                ret.push(keyword.to_owned());
+            }
+        }
+        buf = &buf[5 + size..];
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const XMP: &[u8] = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description xmp:Rating="4"><dc:subject><rdf:Bag><rdf:li>nature</rdf:li><rdf:li>sky &amp; sea</rdf:li></rdf:Bag></dc:subject></rdf:Description></rdf:RDF></x:xmpmeta>"#;
+
+    fn jpeg_segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut ret = vec![0xff, marker];
+        ret.extend(&(data.len() as u16 + 2).to_be_bytes());
+        ret.extend(data);
+        ret
+    }
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut ret = (data.len() as u32).to_be_bytes().to_vec();
+        ret.extend(kind);
+        ret.extend(data);
+        // crc isn't checked
+        ret.extend(&[0; 4]);
+        ret
+    }
+
+    fn webp_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut ret = kind.to_vec();
+        ret.extend(&(data.len() as u32).to_le_bytes());
+        ret.extend(data);
+        if data.len() % 2 == 1 {
+            ret.push(0);
+        }
+        ret
+    }
+
+    fn read(buf: Vec<u8>) -> Metadata {
+        Metadata::from_reader(Cursor::new(buf)).unwrap()
+    }
+
+    #[test]
+    fn jpeg() {
+        let mut iptc = b"8BIM\x04\x04\0\0".to_vec();
+        let records = b"\x1c\x02\x19\0\x05space";
+        iptc.extend(&(records.len() as u32).to_be_bytes());
+        iptc.extend(records);
+        iptc.push(0);
+
+        let mut buf = vec![0xff, 0xd8];
+        buf.extend(jpeg_segment(0xe0, b"JFIF\0\x01\x02"));
+        buf.extend(jpeg_segment(
+            0xe1,
+            &[&b"http://ns.adobe.com/xap/1.0/\0"[..], XMP].concat(),
+        ));
+        buf.extend(jpeg_segment(
+            0xed,
+            &[&b"Photoshop 3.0\0"[..], &iptc].concat(),
+        ));
+        buf.extend(jpeg_segment(0xda, b"\0\0"));
+        // a keyword in the image data doesn't count
+        buf.extend(XMP);
+
+        let meta = read(buf);
+        assert_eq!(meta.rating(), Some(4));
+        assert_eq!(meta.keywords(), vec!["nature", "sky & sea", "space"]);
+    }
+
+    #[test]
+    fn png() {
+        let mut buf = b"\x89PNG\r\n\x1a\n".to_vec();
+        buf.extend(png_chunk(b"IHDR", &[0; 13]));
+        buf.extend(png_chunk(b"IDAT", &[0; 100]));
+        buf.extend(png_chunk(
+            b"iTXt",
+            &[&b"XML:com.adobe.xmp\0\0\0\0\0"[..], XMP].concat(),
+        ));
+        buf.extend(png_chunk(b"IEND", &[]));
+
+        let meta = read(buf);
+        assert_eq!(meta.rating(), Some(4));
+        assert_eq!(meta.keywords(), vec!["nature", "sky & sea"]);
+    }
+
+    #[test]
+    fn compressed_png_text_is_skipped() {
+        let mut buf = b"\x89PNG\r\n\x1a\n".to_vec();
+        buf.extend(png_chunk(
+            b"iTXt",
+            &[&b"XML:com.adobe.xmp\0\x01\0\0\0"[..], XMP].concat(),
+        ));
+        buf.extend(png_chunk(b"IEND", &[]));
+
+        assert_eq!(read(buf).rating(), None);
+    }
+
+    #[test]
+    fn webp() {
+        let mut chunks = webp_chunk(b"VP8 ", &[0; 11]);
+        chunks.extend(webp_chunk(b"XMP ", XMP));
+        let mut buf = b"RIFF".to_vec();
+        buf.extend(&(chunks.len() as u32 + 4).to_le_bytes());
+        buf.extend(b"WEBP");
+        buf.extend(chunks);
+
+        let meta = read(buf);
+        assert_eq!(meta.rating(), Some(4));
+        assert_eq!(meta.keywords(), vec!["nature", "sky & sea"]);
+    }
+
+    #[test]
+    fn truncated_and_unknown_files() {
+        let mut buf = vec![0xff, 0xd8];
+        buf.extend(jpeg_segment(
+            0xe1,
+            &[&b"http://ns.adobe.com/xap/1.0/\0"[..], XMP].concat(),
+        ));
+        buf.extend(&[0xff, 0xe2, 0xff]);
+        assert_eq!(read(buf).rating(), Some(4));
+
+        assert_eq!(read(b"GIF89a".to_vec()).keywords(), Vec::<String>::new());
+        assert_eq!(read([b"BM".to_vec(), XMP.to_vec()].concat()).rating(), None);
+    }
+}
@@ -1,12 +1,17 @@
 mod cfg;
 mod cli;
 mod client;
+mod cron;
 mod daemon;
 mod data;
 mod db;
+mod embedded;
 mod monitor;
 mod rpc;
 mod scan;
+mod schedule;
+mod tag_expr;
+mod tag_source;
 mod unix;
 mod util;
 mod watch_file;
@@ -1,3 +1,9 @@
+mod command;
+mod x11;
+
+pub use command::{Command, CommandConfig};
+pub use x11::X11;
+
 use futures_util::stream::Stream;
 use serde::Deserialize;
 use tokio::stream::StreamExt;
@@ -13,10 +19,86 @@ pub enum Mode {
     Tile,
 }
 
+impl Mode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Fill => "fill",
+            Mode::Tile => "tile",
+        }
+    }
+}
+
+#[derive(Deserialize, Copy, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    Auto,
+    Sway,
+    X11,
+    Command,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Auto
+    }
+}
+
+impl Backend {
+    /// Guesses the backend from the environment of the current session.
+    fn detect(command: Option<&CommandConfig>) -> Result<Self, Error> {
+        let is_set = |var: &str| std::env::var_os(var).map_or(false, |val| !val.is_empty());
+        if is_set("SWAYSOCK") {
+            Ok(Backend::Sway)
+        } else if command.is_some() {
+            Ok(Backend::Command)
+        } else if is_set("WAYLAND_DISPLAY") {
+            Err(Error::new(
+                "Running under an unsupported wayland compositor, configure a command backend",
+            ))
+        } else if is_set("DISPLAY") {
+            Ok(Backend::X11)
+        } else {
+            Err(Error::new(
+                "Can't detect display server, set a backend in the config",
+            ))
+        }
+    }
+}
+
+pub async fn connect(
+    backend: Backend,
+    command: Option<&CommandConfig>,
+) -> Result<Box<dyn Monitor>, Error> {
+    let backend = match backend {
+        Backend::Auto => Backend::detect(command)?,
+        backend => backend,
+    };
+    tracing::info!("Using {:?} backend", backend);
+
+    Ok(match backend {
+        Backend::Sway => Box::new(Sway::new().await?),
+        Backend::X11 => Box::new(X11::new().await?),
+        Backend::Command => match command {
+            Some(cfg) => Box::new(Command::new(cfg.clone())),
+            None => return Err(Error::new("Command backend needs a [command] section")),
+        },
+        Backend::Auto => unreachable!(),
+    })
+}
+
 #[async_trait::async_trait]
 pub trait Monitor {
     async fn idents(&mut self) -> Result<Vec<String>, Error>;
     async fn set_wallpaper(&mut self, mode: Mode, ident: &str, path: &str) -> Result<(), Error>;
+    /// Shows the wallpapers set since the last commit, for backends that draw every output at
+    /// once.
+    async fn commit(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Size of an output in pixels if the backend knows it.
+    async fn resolution(&mut self, _ident: &str) -> Result<Option<(u32, u32)>, Error> {
+        Ok(None)
+    }
     async fn display_changed(
         &self,
     ) -> Result<Box<dyn Stream<Item = Result<(), Error>> + Unpin>, Error>;
@@ -41,13 +123,13 @@ impl Monitor for Sway {
     }
 
     async fn set_wallpaper(&mut self, mode: Mode, ident: &str, path: &str) -> Result<(), Error> {
-        let mode = match mode {
-            Mode::Fill => "fill",
-            Mode::Tile => "tile",
-        };
-
         let escaped_path = path.replace('"', "\"");
-        let cmd = format!(r#"output {} background "{}" {}"#, ident, escaped_path, mode);
+        let cmd = format!(
+            r#"output {} background "{}" {}"#,
+            ident,
+            escaped_path,
+            mode.as_str()
+        );
 
         self.0
             .run_command(&cmd)
@@ -66,6 +148,14 @@ impl Monitor for Sway {
             })
     }
 
+    async fn resolution(&mut self, ident: &str) -> Result<Option<(u32, u32)>, Error> {
+        self.0.get_outputs().await.map_err(Error::new).map(|out| {
+            out.into_iter()
+                .find(|out| out.name == ident)
+                .map(|out| (out.rect.width as u32, out.rect.height as u32))
+        })
+    }
+
     async fn display_changed(
         &self,
     ) -> Result<Box<dyn Stream<Item = Result<(), Error>> + Unpin>, Error> {
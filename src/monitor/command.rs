@@ -0,0 +1,117 @@
+use super::{Error, Mode, Monitor};
+use futures_util::stream::{Stream, StreamExt};
+use serde::Deserialize;
+use std::process::Stdio;
+use tokio::{io::AsyncBufReadExt, process, sync::mpsc};
+
+/// Shell command templates for driving external wallpaper setters.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CommandConfig {
+    /// Prints one output name per line
+    pub outputs: String,
+    /// Sets a wallpaper, supports the `{output}`, `{path}` and `{mode}` placeholders
+    pub set_wallpaper: String,
+    /// Keeps running and prints a line every time the displays change
+    pub display_changed: Option<String>,
+}
+
+pub struct Command(CommandConfig);
+
+impl Command {
+    pub fn new(cfg: CommandConfig) -> Self {
+        Self(cfg)
+    }
+}
+
+fn shell(cmd: &str) -> process::Command {
+    let mut ret = process::Command::new("sh");
+    ret.arg("-c").arg(cmd).stdin(Stdio::null());
+    ret
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+fn fill_template(template: &str, mode: Mode, ident: &str, path: &str) -> String {
+    template
+        .replace("{output}", &quote(ident))
+        .replace("{path}", &quote(path))
+        .replace("{mode}", mode.as_str())
+}
+
+#[async_trait::async_trait]
+impl Monitor for Command {
+    async fn idents(&mut self) -> Result<Vec<String>, Error> {
+        let out = shell(&self.0.outputs)
+            .stderr(Stdio::inherit())
+            .output()
+            .await
+            .map_err(Error::new)?;
+        if !out.status.success() {
+            return Err(Error::new(format!(
+                "Output list command failed with {}",
+                out.status
+            )));
+        }
+
+        Ok(String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    async fn set_wallpaper(&mut self, mode: Mode, ident: &str, path: &str) -> Result<(), Error> {
+        // NOTE: stdout is not captured so long running setters can just be
+        // backgrounded with `&` in the template
+        let status = shell(&fill_template(&self.0.set_wallpaper, mode, ident, path))
+            .stdout(Stdio::null())
+            .status()
+            .await
+            .map_err(Error::new)?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::new(format!(
+                "Can't set wallpaper: command failed with {}",
+                status
+            )))
+        }
+    }
+
+    async fn display_changed(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<(), Error>> + Unpin>, Error> {
+        let cmd = match &self.0.display_changed {
+            Some(cmd) => cmd,
+            None => return Ok(Box::new(futures_util::stream::pending())),
+        };
+
+        let mut child = shell(cmd)
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(Error::new)?;
+        let mut lines = tokio::io::BufReader::new(child.stdout.take().unwrap()).lines();
+
+        let (mut tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            // keep the child alive for as long as someone listens
+            let _child = child;
+            while let Some(line) = lines.next().await {
+                let ev = line.map(|_| ()).map_err(Error::new);
+                if let Err(_) = tx.send(ev).await {
+                    return;
+                }
+            }
+            let _ = tx
+                .send(Err(Error::new("Display change command stopped")))
+                .await;
+        });
+
+        Ok(Box::new(rx))
+    }
+}
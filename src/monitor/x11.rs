@@ -0,0 +1,518 @@
+use super::{Error, Mode, Monitor};
+use futures_util::stream::Stream;
+use image::{imageops, RgbaImage};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task,
+};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        randr::{self, ConnectionExt as _},
+        xproto::{self, ConnectionExt as _},
+        Event,
+    },
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+};
+
+struct Output {
+    name: String,
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+}
+
+struct Wallpaper {
+    path: String,
+    mode: Mode,
+    image: RgbaImage,
+}
+
+pub struct X11 {
+    /// `None` means `$DISPLAY`
+    display: Option<String>,
+    cxn: Arc<RustConnection>,
+    screen: usize,
+    wallpapers: BTreeMap<String, Wallpaper>,
+    /// Whether wallpapers changed since the last commit
+    staged: bool,
+    /// The root pixmap made by this connection, freed when it gets replaced
+    pixmap: Option<xproto::Pixmap>,
+}
+
+impl X11 {
+    pub async fn new() -> Result<Self, Error> {
+        Self::connect(None).await
+    }
+
+    pub async fn connect(display: Option<String>) -> Result<Self, Error> {
+        task::spawn_blocking(move || {
+            let (cxn, screen) = RustConnection::connect(display.as_deref()).map_err(Error::new)?;
+            // fails if the server doesn't know about randr
+            cxn.randr_query_version(1, 2)
+                .map_err(Error::new)?
+                .reply()
+                .map_err(Error::new)?;
+            // the root pixmap needs to outlive this connection so compositors and
+            // other programs can still read it after pickwp exits
+            cxn.set_close_down_mode(xproto::CloseDown::RETAIN_PERMANENT)
+                .map_err(Error::new)?;
+            Ok(Self {
+                display,
+                cxn: Arc::new(cxn),
+                screen,
+                wallpapers: Default::default(),
+                staged: false,
+                pixmap: None,
+            })
+        })
+        .await
+        .unwrap()
+    }
+}
+
+fn outputs(cxn: &RustConnection, root: xproto::Window) -> Result<Vec<Output>, Error> {
+    let resources = cxn
+        .randr_get_screen_resources_current(root)
+        .map_err(Error::new)?
+        .reply()
+        .map_err(Error::new)?;
+
+    let mut ret = Vec::with_capacity(resources.outputs.len());
+    for output in resources.outputs {
+        let info = cxn
+            .randr_get_output_info(output, resources.config_timestamp)
+            .map_err(Error::new)?
+            .reply()
+            .map_err(Error::new)?;
+        // disconnected or disabled
+        if info.crtc == x11rb::NONE {
+            continue;
+        }
+
+        let crtc = cxn
+            .randr_get_crtc_info(info.crtc, resources.config_timestamp)
+            .map_err(Error::new)?
+            .reply()
+            .map_err(Error::new)?;
+        ret.push(Output {
+            name: String::from_utf8_lossy(&info.name).into_owned(),
+            x: crtc.x,
+            y: crtc.y,
+            width: crtc.width,
+            height: crtc.height,
+        });
+    }
+
+    Ok(ret)
+}
+
+fn load_wallpaper(path: &str, mode: Mode, width: u16, height: u16) -> Result<RgbaImage, Error> {
+    let img = image::open(path).map_err(Error::new)?;
+    let (width, height) = (u32::from(width), u32::from(height));
+    Ok(match mode {
+        Mode::Fill => img
+            .resize_to_fill(width, height, imageops::FilterType::Triangle)
+            .into_rgba8(),
+        Mode::Tile => {
+            let mut out = RgbaImage::new(width, height);
+            imageops::tile(&mut out, &img.into_rgba8());
+            out
+        }
+    })
+}
+
+fn atom(cxn: &RustConnection, name: &str) -> Result<xproto::Atom, Error> {
+    cxn.intern_atom(false, name.as_bytes())
+        .map_err(Error::new)?
+        .reply()
+        .map_err(Error::new)
+        .map(|reply| reply.atom)
+}
+
+/// Draws all wallpapers into a new pixmap and makes it the root window background.
+/// Returns the new pixmap, `old` is the one this connection made last time.
+fn set_root_pixmap(
+    cxn: &RustConnection,
+    screen: usize,
+    outputs: &[Output],
+    wallpapers: &BTreeMap<String, Wallpaper>,
+    old: Option<xproto::Pixmap>,
+) -> Result<xproto::Pixmap, Error> {
+    let setup = cxn.setup();
+    let screen = &setup.roots[screen];
+    let root = screen.root;
+    let depth = screen.root_depth;
+
+    match setup.pixmap_formats.iter().find(|fmt| fmt.depth == depth) {
+        Some(fmt) if fmt.bits_per_pixel == 32 => (),
+        _ => {
+            return Err(Error::new(format!(
+                "Unsupported root window depth {}",
+                depth
+            )))
+        }
+    }
+
+    let geometry = cxn
+        .get_geometry(root)
+        .map_err(Error::new)?
+        .reply()
+        .map_err(Error::new)?;
+
+    let mut canvas = RgbaImage::new(u32::from(geometry.width), u32::from(geometry.height));
+    for output in outputs {
+        if let Some(wp) = wallpapers.get(&output.name) {
+            imageops::replace(
+                &mut canvas,
+                &wp.image,
+                output.x.max(0) as u32,
+                output.y.max(0) as u32,
+            );
+        }
+    }
+
+    // convert to the server's pixel layout in place
+    let lsb_first = setup.image_byte_order == xproto::ImageOrder::LSB_FIRST;
+    let mut data = canvas.into_raw();
+    for px in data.chunks_exact_mut(4) {
+        let (r, g, b) = (px[0], px[1], px[2]);
+        px.copy_from_slice(&if lsb_first {
+            [b, g, r, 0]
+        } else {
+            [0, r, g, b]
+        });
+    }
+
+    let pixmap = cxn.generate_id().map_err(Error::new)?;
+    cxn.create_pixmap(depth, pixmap, root, geometry.width, geometry.height)
+        .map_err(Error::new)?;
+    let gc = cxn.generate_id().map_err(Error::new)?;
+    cxn.create_gc(gc, pixmap, &Default::default())
+        .map_err(Error::new)?;
+
+    // the image is way bigger than the maximum request size so send it in chunks of rows
+    let stride = usize::from(geometry.width) * 4;
+    let rows_per_request = ((cxn.maximum_request_bytes() - 64) / stride).max(1);
+    for (i, chunk) in data.chunks(stride * rows_per_request).enumerate() {
+        cxn.put_image(
+            xproto::ImageFormat::Z_PIXMAP,
+            pixmap,
+            gc,
+            geometry.width,
+            (chunk.len() / stride) as u16,
+            0,
+            (i * rows_per_request) as i16,
+            0,
+            depth,
+            chunk,
+        )
+        .map_err(Error::new)?;
+    }
+    cxn.free_gc(gc).map_err(Error::new)?;
+
+    let xrootpmap_id = atom(cxn, "_XROOTPMAP_ID")?;
+    let esetroot_pmap_id = atom(cxn, "ESETROOT_PMAP_ID")?;
+
+    let previous = cxn
+        .get_property(
+            false,
+            root,
+            esetroot_pmap_id,
+            xproto::AtomEnum::PIXMAP,
+            0,
+            1,
+        )
+        .map_err(Error::new)?
+        .reply()
+        .map_err(Error::new)?
+        .value32()
+        .and_then(|mut value| value.next());
+
+    for property in &[xrootpmap_id, esetroot_pmap_id] {
+        cxn.change_property32(
+            xproto::PropMode::REPLACE,
+            root,
+            *property,
+            xproto::AtomEnum::PIXMAP,
+            &[pixmap],
+        )
+        .map_err(Error::new)?;
+    }
+
+    cxn.change_window_attributes(
+        root,
+        &xproto::ChangeWindowAttributesAux::new().background_pixmap(pixmap),
+    )
+    .map_err(Error::new)?;
+    cxn.clear_area(false, root, 0, 0, 0, 0)
+        .map_err(Error::new)?;
+
+    match (old, previous) {
+        // killing the client of our own pixmap would kill this connection
+        (Some(old), _) => {
+            cxn.free_pixmap(old).map_err(Error::new)?;
+        }
+        // left over from the last run or another tool, this is what every other tool does too
+        (None, Some(previous)) => {
+            let _ = cxn.kill_client(previous);
+        }
+        (None, None) => (),
+    }
+    cxn.sync().map_err(Error::new)?;
+
+    Ok(pixmap)
+}
+
+#[async_trait::async_trait]
+impl Monitor for X11 {
+    async fn idents(&mut self) -> Result<Vec<String>, Error> {
+        let cxn = self.cxn.clone();
+        let root = cxn.setup().roots[self.screen].root;
+        task::spawn_blocking(move || outputs(&cxn, root))
+            .await
+            .unwrap()
+            .map(|outputs| outputs.into_iter().map(|output| output.name).collect())
+    }
+
+    async fn resolution(&mut self, ident: &str) -> Result<Option<(u32, u32)>, Error> {
+        let cxn = self.cxn.clone();
+        let root = cxn.setup().roots[self.screen].root;
+        let outputs = task::spawn_blocking(move || outputs(&cxn, root))
+            .await
+            .unwrap()?;
+        Ok(outputs
+            .into_iter()
+            .find(|output| output.name == ident)
+            .map(|output| (u32::from(output.width), u32::from(output.height))))
+    }
+
+    async fn set_wallpaper(&mut self, mode: Mode, ident: &str, path: &str) -> Result<(), Error> {
+        let cxn = self.cxn.clone();
+        let root = cxn.setup().roots[self.screen].root;
+        let ident = ident.to_owned();
+        let path = path.to_owned();
+
+        let (ident, wallpaper) = task::spawn_blocking(move || {
+            let outputs = outputs(&cxn, root)?;
+            let output = outputs
+                .iter()
+                .find(|output| output.name == ident)
+                .ok_or_else(|| Error::new(format!("Output {} does not exist", ident)))?;
+
+            let image = load_wallpaper(&path, mode, output.width, output.height)?;
+            Ok::<_, Error>((ident, Wallpaper { path, mode, image }))
+        })
+        .await
+        .unwrap()?;
+
+        self.wallpapers.insert(ident, wallpaper);
+        self.staged = true;
+        Ok(())
+    }
+
+    /// Draws all outputs into one root pixmap.
+    async fn commit(&mut self) -> Result<(), Error> {
+        if !self.staged {
+            return Ok(());
+        }
+
+        let cxn = self.cxn.clone();
+        let screen = self.screen;
+        let old = self.pixmap;
+        let mut wallpapers = std::mem::take(&mut self.wallpapers);
+
+        let (ret, wallpapers) = task::spawn_blocking(move || {
+            let ret = (|| -> Result<xproto::Pixmap, Error> {
+                let root = cxn.setup().roots[screen].root;
+                let outputs = outputs(&cxn, root)?;
+
+                // outputs might have been resized since their wallpaper was loaded
+                for output in &outputs {
+                    if let Some(wp) = wallpapers.get_mut(&output.name) {
+                        if wp.image.dimensions()
+                            != (u32::from(output.width), u32::from(output.height))
+                        {
+                            wp.image =
+                                load_wallpaper(&wp.path, wp.mode, output.width, output.height)?;
+                        }
+                    }
+                }
+
+                set_root_pixmap(&cxn, screen, &outputs, &wallpapers, old)
+            })();
+            (ret, wallpapers)
+        })
+        .await
+        .unwrap();
+
+        self.wallpapers = wallpapers;
+        self.pixmap = Some(ret?);
+        self.staged = false;
+        Ok(())
+    }
+
+    async fn display_changed(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = Result<(), Error>> + Unpin>, Error> {
+        let screen = self.screen;
+        let display = self.display.clone();
+        let (mut tx, rx) = mpsc::channel(1);
+        let cxn = task::spawn_blocking(move || -> Result<_, Error> {
+            let (cxn, _) = RustConnection::connect(display.as_deref()).map_err(Error::new)?;
+            let root = cxn.setup().roots[screen].root;
+            cxn.randr_select_input(
+                root,
+                randr::NotifyMask::SCREEN_CHANGE
+                    | randr::NotifyMask::CRTC_CHANGE
+                    | randr::NotifyMask::OUTPUT_CHANGE,
+            )
+            .map_err(Error::new)?;
+            cxn.flush().map_err(Error::new)?;
+            Ok(cxn)
+        })
+        .await
+        .unwrap()?;
+
+        task::spawn_blocking(move || loop {
+            let ev = match cxn.wait_for_event() {
+                Ok(Event::RandrScreenChangeNotify(_)) | Ok(Event::RandrNotify(_)) => Ok(()),
+                Ok(_) => continue,
+                Err(e) => Err(Error::new(e)),
+            };
+            let fatal = ev.is_err();
+            match tx.try_send(ev) {
+                // one pending change is enough
+                Ok(_) | Err(TrySendError::Full(_)) => (),
+                Err(TrySendError::Closed(_)) => return,
+            }
+            if fatal {
+                return;
+            }
+        });
+
+        Ok(Box::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        path::Path,
+        process::{Child, Command, Stdio},
+        time::{Duration, Instant},
+    };
+
+    /// Display number that hopefully isn't taken
+    const DISPLAY: u32 = 87;
+
+    struct Xvfb(Child);
+
+    impl Xvfb {
+        /// `None` if Xvfb isn't installed.
+        fn start() -> Option<Self> {
+            let child = match Command::new("Xvfb")
+                .arg(format!(":{}", DISPLAY))
+                .args(&["-screen", "0", "320x240x24", "-nolisten", "tcp"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+            {
+                Ok(child) => child,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+                Err(e) => panic!("Can't start Xvfb: {}", e),
+            };
+
+            let socket = format!("/tmp/.X11-unix/X{}", DISPLAY);
+            let start = Instant::now();
+            while !Path::new(&socket).exists() {
+                assert!(
+                    start.elapsed() < Duration::from_secs(10),
+                    "Xvfb didn't start"
+                );
+                std::thread::sleep(Duration::from_millis(50));
+            }
+
+            Some(Self(child))
+        }
+    }
+
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    #[tokio::test]
+    async fn sets_root_pixmap() {
+        let _xvfb = match Xvfb::start() {
+            Some(xvfb) => xvfb,
+            None => {
+                eprintln!("Xvfb is not installed, skipping");
+                return;
+            }
+        };
+        let display = format!(":{}", DISPLAY);
+
+        let path = std::env::temp_dir().join("pickwp-x11-test.png");
+        RgbaImage::from_pixel(16, 16, image::Rgba([255, 0, 0, 255]))
+            .save(&path)
+            .unwrap();
+
+        let mut x11 = X11::connect(Some(display.clone())).await.unwrap();
+        let idents = x11.idents().await.unwrap();
+        assert!(!idents.is_empty());
+        for ident in &idents {
+            x11.set_wallpaper(Mode::Fill, ident, path.to_str().unwrap())
+                .await
+                .unwrap();
+        }
+        x11.commit().await.unwrap();
+        let pixmap = x11.pixmap.unwrap();
+
+        // read it back like a compositor would
+        let (cxn, screen) = RustConnection::connect(Some(&display)).unwrap();
+        let root = cxn.setup().roots[screen].root;
+        let property = cxn
+            .get_property(
+                false,
+                root,
+                atom(&cxn, "_XROOTPMAP_ID").unwrap(),
+                xproto::AtomEnum::PIXMAP,
+                0,
+                1,
+            )
+            .unwrap()
+            .reply()
+            .unwrap();
+        assert_eq!(property.value32().unwrap().next(), Some(pixmap));
+
+        let pixel = cxn
+            .get_image(xproto::ImageFormat::Z_PIXMAP, pixmap, 0, 0, 1, 1, !0)
+            .unwrap()
+            .reply()
+            .unwrap()
+            .data;
+        let lsb_first = cxn.setup().image_byte_order == xproto::ImageOrder::LSB_FIRST;
+        let rgb = if lsb_first {
+            [pixel[2], pixel[1], pixel[0]]
+        } else {
+            [pixel[1], pixel[2], pixel[3]]
+        };
+        assert_eq!(rgb, [255, 0, 0]);
+
+        // a second refresh replaces the pixmap on the same connection
+        x11.set_wallpaper(Mode::Tile, &idents[0], path.to_str().unwrap())
+            .await
+            .unwrap();
+        x11.commit().await.unwrap();
+        assert_ne!(x11.pixmap, Some(pixmap));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -5,9 +5,12 @@ mod tarpc_unix_transport;
 pub use client::{connect, Error as ClientError};
 pub use server::{bind, Error as ServerError};
 
+use crate::data::{Duplicates, HistoryEntry, Rating, RetagStats};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 #[tarpc::service]
@@ -16,6 +19,56 @@ pub trait PickwpService {
     async fn scan();
     async fn get_wallpapers() -> BTreeMap<String, Option<String>>;
     async fn toggle_freeze() -> bool;
+    async fn history(
+        output: Option<String>,
+        since: Option<Duration>,
+        limit: Option<u32>,
+    ) -> Result<Vec<HistoryEntry>, RemoteError>;
+    async fn profiles() -> Vec<String>;
+    async fn current_profile() -> Option<String>;
+    async fn set_profile(name: Option<String>) -> Result<(), RemoteError>;
+    /// Retags everything below `paths` or every file if `None`.
+    async fn retag(paths: Option<Vec<PathBuf>>) -> Result<RetagStats, RemoteError>;
+    async fn duplicates() -> Result<Vec<Duplicates>, RemoteError>;
+    /// Shows `path` on `output` until its next refresh.
+    async fn set(output: String, path: PathBuf) -> Result<(), RemoteError>;
+    /// Keeps showing `path` on `output`, unpins it if `None`.
+    async fn pin(output: String, path: Option<PathBuf>) -> Result<(), RemoteError>;
+    /// Goes back in the history of `output` or every output if `None`.
+    async fn prev(output: Option<String>) -> Result<(), RemoteError>;
+    /// Goes forward in the history of `output` or every output if `None`.
+    /// Picks a new wallpaper when there's nothing newer.
+    async fn next(output: Option<String>) -> Result<(), RemoteError>;
+    async fn ban(wp: WpSelector, banned: bool) -> Result<(), RemoteError>;
+    async fn favorite(wp: WpSelector, favorite: bool) -> Result<(), RemoteError>;
+    /// Sets the rating of `wp`, removes it if `None`.
+    async fn rate(wp: WpSelector, rating: Option<Rating>) -> Result<(), RemoteError>;
+}
+
+/// Which wallpaper a request is about.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WpSelector {
+    Path(PathBuf),
+    /// The wallpaper shown on an output, the output can be left out if there's only one
+    Current(Option<String>),
+}
+
+/// An error that happened inside of the daemon while handling a request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteError(String);
+
+impl RemoteError {
+    pub fn new(e: impl std::fmt::Display) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
 }
 
 struct RtPath {
@@ -1,6 +1,9 @@
 use crate::{
     daemon,
-    rpc::PickwpService,
+    data::{Duplicates, HistoryEntry, Rating, RelativePath, RetagStats, UnixTimestamp},
+    db,
+    rpc::{PickwpService, RemoteError, WpSelector},
+    scan::RootHandle,
     unix::{mkdir, LockFile, LockFileError},
 };
 use futures_util::{future::Future, stream::StreamExt};
@@ -8,7 +11,9 @@ use nix::sys::stat::Mode;
 use snafu::ResultExt;
 use std::{
     collections::BTreeMap,
+    convert::TryFrom,
     path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 use tarpc::context::Context;
 use tokio::net::UnixListener;
@@ -73,7 +78,7 @@ impl Listener {
 impl super::PickwpService for daemon::State {
     async fn refresh(self, _: Context) {
         if let Some(state) = self.lock().await.as_mut() {
-            state.refresh_preempt.preempt().await;
+            state.refresh();
         }
     }
 
@@ -85,7 +90,11 @@ impl super::PickwpService for daemon::State {
 
     async fn get_wallpapers(self, _: Context) -> BTreeMap<String, Option<String>> {
         if let Some(state) = self.lock().await.as_ref() {
-            state.current_wps.clone()
+            state
+                .current_wps
+                .iter()
+                .map(|(output, wp)| (output.clone(), wp.as_ref().map(|wp| wp.path.clone())))
+                .collect()
         } else {
             Default::default()
         }
@@ -94,11 +103,395 @@ impl super::PickwpService for daemon::State {
     async fn toggle_freeze(self, _: Context) -> bool {
         if let Some(state) = self.lock().await.as_mut() {
             state.frozen = !state.frozen;
+            let stored = match self.pool.acquire().await {
+                Ok(mut cxn) => {
+                    db::store_setting(
+                        &mut cxn,
+                        daemon::FROZEN_SETTING,
+                        if state.frozen { Some("true") } else { None },
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = stored {
+                tracing::error!("Can't store frozen state: {}", e);
+            }
             state.frozen
         } else {
             false
         }
     }
+
+    async fn history(
+        self,
+        _: Context,
+        output: Option<String>,
+        since: Option<Duration>,
+        limit: Option<u32>,
+    ) -> Result<Vec<HistoryEntry>, RemoteError> {
+        let since = since
+            .and_then(|since| SystemTime::now().checked_sub(since))
+            .map(UnixTimestamp::from)
+            .unwrap_or(UnixTimestamp::from(std::i64::MIN));
+        let mut cxn = self.pool.acquire().await.map_err(RemoteError::new)?;
+        db::fetch_history(&mut cxn, output.as_deref(), since, limit)
+            .await
+            .map_err(RemoteError::new)
+    }
+
+    async fn profiles(self, _: Context) -> Vec<String> {
+        if let Some(state) = self.lock().await.as_ref() {
+            state.profiles.clone()
+        } else {
+            Default::default()
+        }
+    }
+
+    async fn current_profile(self, _: Context) -> Option<String> {
+        self.lock()
+            .await
+            .as_ref()
+            .and_then(|state| state.profile.clone())
+    }
+
+    async fn set_profile(self, _: Context, name: Option<String>) -> Result<(), RemoteError> {
+        let mut state = self.lock().await;
+        let state = match state.as_mut() {
+            Some(state) => state,
+            None => return Err(RemoteError::new("Daemon is not ready")),
+        };
+
+        if let Some(name) = &name {
+            if !state.profiles.contains(name) {
+                return Err(RemoteError::new(format!("Profile {} does not exist", name)));
+            }
+        }
+
+        let mut cxn = self.pool.acquire().await.map_err(RemoteError::new)?;
+        db::store_setting(&mut cxn, daemon::PROFILE_SETTING, name.as_deref())
+            .await
+            .map_err(RemoteError::new)?;
+
+        state.profile = name;
+        state.refresh();
+        Ok(())
+    }
+
+    async fn retag(
+        self,
+        _: Context,
+        paths: Option<Vec<PathBuf>>,
+    ) -> Result<RetagStats, RemoteError> {
+        // don't block everything else while retagging
+        let roots = match self.lock().await.as_ref() {
+            Some(state) => state.roots.clone(),
+            None => return Err(RemoteError::new("Daemon is not ready")),
+        };
+
+        // paths to retag per root, `None` for the whole root
+        let jobs = match paths {
+            Some(paths) => {
+                let mut jobs = vec![Some(Vec::new()); roots.len()];
+                for path in &paths {
+                    let (i, stripped) = find_root(&roots, path).await?;
+                    // the whole wallpaper directory
+                    if stripped.as_os_str().is_empty() {
+                        jobs[i] = None;
+                    } else if let Some(relative) = &mut jobs[i] {
+                        relative.push(
+                            RelativePath::try_from(stripped.to_owned())
+                                .map_err(RemoteError::new)?,
+                        );
+                    }
+                }
+                jobs
+            }
+            None => vec![None; roots.len()],
+        };
+
+        let mut stats = RetagStats::default();
+        for (root, paths) in roots.iter().zip(jobs) {
+            if paths.as_ref().map_or(false, Vec::is_empty) {
+                continue;
+            }
+            let root_stats = root
+                .retag(&self.pool, paths)
+                .await
+                .map_err(RemoteError::new)?;
+            stats.files += root_stats.files;
+            stats.added += root_stats.added;
+            stats.removed += root_stats.removed;
+        }
+        Ok(stats)
+    }
+
+    async fn duplicates(self, _: Context) -> Result<Vec<Duplicates>, RemoteError> {
+        let mut cxn = self.pool.acquire().await.map_err(RemoteError::new)?;
+        db::fetch_duplicates(&mut cxn)
+            .await
+            .map_err(RemoteError::new)
+    }
+
+    async fn set(self, _: Context, output: String, path: PathBuf) -> Result<(), RemoteError> {
+        let roots = self.roots_of_output(&output).await?;
+        let wp = resolve_wp(&self.pool, &roots, &path).await?;
+
+        let mut state = self.lock().await;
+        let state = state
+            .as_mut()
+            .ok_or_else(|| RemoteError::new("Daemon is not ready"))?;
+        state.requested.insert(output.clone(), wp);
+        state.refresh_output(&output);
+        Ok(())
+    }
+
+    async fn pin(
+        self,
+        _: Context,
+        output: String,
+        path: Option<PathBuf>,
+    ) -> Result<(), RemoteError> {
+        let wp = match path {
+            Some(path) => {
+                let roots = self.roots_of_output(&output).await?;
+                Some(resolve_wp(&self.pool, &roots, &path).await?)
+            }
+            None => None,
+        };
+
+        let mut state = self.lock().await;
+        let state = state
+            .as_mut()
+            .ok_or_else(|| RemoteError::new("Daemon is not ready"))?;
+        match wp {
+            Some(wp) => {
+                state.pinned.insert(output.clone(), wp);
+                state.refresh_output(&output);
+            }
+            // keeps showing the pinned wallpaper until the next refresh
+            None => {
+                state.pinned.remove(&output);
+                let mut cxn = self.pool.acquire().await.map_err(RemoteError::new)?;
+                db::unpin_output_wp(&mut cxn, &output)
+                    .await
+                    .map_err(RemoteError::new)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn prev(self, _: Context, output: Option<String>) -> Result<(), RemoteError> {
+        self.navigate(output, Direction::Prev).await
+    }
+
+    async fn next(self, _: Context, output: Option<String>) -> Result<(), RemoteError> {
+        self.navigate(output, Direction::Next).await
+    }
+
+    async fn ban(self, _: Context, wp: WpSelector, banned: bool) -> Result<(), RemoteError> {
+        let wp = self.select_wp(wp).await?;
+        let mut cxn = self.pool.acquire().await.map_err(RemoteError::new)?;
+        db::set_banned(&mut cxn, wp.id, banned)
+            .await
+            .map_err(RemoteError::new)?;
+
+        if banned {
+            if let Some(state) = self.lock().await.as_mut() {
+                let showing = state
+                    .current_wps
+                    .iter()
+                    .filter(|(_, current)| {
+                        current.as_ref().map(|current| current.id) == Some(wp.id)
+                    })
+                    .map(|(output, _)| output.clone())
+                    .collect::<Vec<_>>();
+                for output in showing {
+                    state.refresh_output(&output);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn favorite(self, _: Context, wp: WpSelector, favorite: bool) -> Result<(), RemoteError> {
+        let wp = self.select_wp(wp).await?;
+        let mut cxn = self.pool.acquire().await.map_err(RemoteError::new)?;
+        db::set_favorite(&mut cxn, wp.id, favorite)
+            .await
+            .map_err(RemoteError::new)
+    }
+
+    async fn rate(
+        self,
+        _: Context,
+        wp: WpSelector,
+        rating: Option<Rating>,
+    ) -> Result<(), RemoteError> {
+        let wp = self.select_wp(wp).await?;
+        let mut cxn = self.pool.acquire().await.map_err(RemoteError::new)?;
+        db::set_rating(&mut cxn, wp.id, rating)
+            .await
+            .map_err(RemoteError::new)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Prev,
+    Next,
+}
+
+impl daemon::State {
+    async fn select_wp(&self, wp: WpSelector) -> Result<daemon::CurrentWp, RemoteError> {
+        match wp {
+            WpSelector::Path(path) => {
+                // indexing might take a while so don't hold the lock
+                let roots = match self.lock().await.as_ref() {
+                    Some(state) => state.roots.clone(),
+                    None => return Err(RemoteError::new("Daemon is not ready")),
+                };
+                resolve_wp(&self.pool, &roots, &path).await
+            }
+            WpSelector::Current(output) => {
+                let state = self.lock().await;
+                let state = state
+                    .as_ref()
+                    .ok_or_else(|| RemoteError::new("Daemon is not ready"))?;
+                let shown = state
+                    .current_wps
+                    .iter()
+                    .filter(|(name, _)| output.as_ref().map_or(true, |output| output == *name))
+                    .collect::<Vec<_>>();
+                match shown.as_slice() {
+                    [(_, Some(wp))] => Ok(wp.clone()),
+                    [(name, None)] => {
+                        Err(RemoteError::new(format!("Nothing is shown on {}", name)))
+                    }
+                    [] => Err(RemoteError::new(match output {
+                        Some(output) => format!("Unknown output {}", output),
+                        None => "There are no outputs".to_owned(),
+                    })),
+                    _ => Err(RemoteError::new(
+                        "There are multiple outputs, pick one with --output",
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Shows the previous or next wallpaper in the history of `output` or every output if `None`.
+    async fn navigate(
+        &self,
+        output: Option<String>,
+        direction: Direction,
+    ) -> Result<(), RemoteError> {
+        let mut state = self.lock().await;
+        let state = state
+            .as_mut()
+            .ok_or_else(|| RemoteError::new("Daemon is not ready"))?;
+
+        let outputs = match output {
+            Some(output) if !state.refresh_preempts.contains_key(&output) => {
+                return Err(RemoteError::new(format!("Unknown output {}", output)));
+            }
+            Some(output) if state.pinned.contains_key(&output) => {
+                return Err(RemoteError::new(format!("{} is pinned", output)));
+            }
+            Some(output) => vec![output],
+            None => state
+                .refresh_preempts
+                .keys()
+                .filter(|output| !state.pinned.contains_key(*output))
+                .cloned()
+                .collect(),
+        };
+
+        for output in outputs {
+            let history = state.history.entry(output.clone()).or_default();
+            let wp = match direction {
+                Direction::Prev => history.prev(),
+                Direction::Next => history.next(),
+            };
+            match wp {
+                Some(wp) => {
+                    let wp = wp.clone();
+                    state.requested.insert(output.clone(), wp);
+                }
+                // already at the oldest one
+                None if direction == Direction::Prev => continue,
+                // already at the newest one, pick a new one
+                None => (),
+            }
+            state.refresh_output(&output);
+        }
+
+        Ok(())
+    }
+
+    /// All wallpaper directories, fails if `output` doesn't exist.
+    async fn roots_of_output(&self, output: &str) -> Result<Vec<RootHandle>, RemoteError> {
+        match self.lock().await.as_ref() {
+            Some(state) if state.refresh_preempts.contains_key(output) => Ok(state.roots.clone()),
+            Some(_) => Err(RemoteError::new(format!("Unknown output {}", output))),
+            None => Err(RemoteError::new("Daemon is not ready")),
+        }
+    }
+}
+
+/// Index of the root that contains `path` and `path` relative to it.
+/// The innermost root wins if they are nested.
+async fn find_root<'a>(
+    roots: &[RootHandle],
+    path: &'a Path,
+) -> Result<(usize, &'a Path), RemoteError> {
+    // clients send canonical paths but configured ones might go through symlinks
+    let mut canonical = Vec::with_capacity(roots.len());
+    for root in roots {
+        let configured = root.root().path();
+        canonical.push(
+            tokio::fs::canonicalize(configured)
+                .await
+                .unwrap_or_else(|_| configured.to_owned()),
+        );
+    }
+
+    roots
+        .iter()
+        .zip(&canonical)
+        .enumerate()
+        .filter_map(|(i, (root, canonical))| {
+            path.strip_prefix(canonical)
+                .or_else(|_| path.strip_prefix(root.root().path()))
+                .ok()
+                .map(|stripped| (i, stripped))
+        })
+        .min_by_key(|(_, stripped)| stripped.components().count())
+        .ok_or_else(|| {
+            RemoteError::new(format!(
+                "{} is not in a wallpaper directory",
+                path.display()
+            ))
+        })
+}
+
+/// Looks up `path` in the index, indexing it first if it's new.
+async fn resolve_wp(
+    pool: &sqlx::SqlitePool,
+    roots: &[RootHandle],
+    path: &Path,
+) -> Result<daemon::CurrentWp, RemoteError> {
+    let (i, stripped) = find_root(roots, path).await?;
+    let relative = RelativePath::try_from(stripped.to_owned()).map_err(RemoteError::new)?;
+    let id = roots[i]
+        .index_path(pool, &relative)
+        .await
+        .map_err(RemoteError::new)?;
+    Ok(daemon::CurrentWp {
+        id,
+        path: roots[i].root().root(&relative),
+    })
 }
 
 pub fn bind(rtdir: impl AsRef<Path>) -> Result<Listener, Error> {
@@ -1,10 +1,25 @@
 use crate::{
-    data::{PathData, RelativePath, Time},
-    db::{self, RootData, RootId},
+    cfg::ScanConfig,
+    data::{ContentHash, Dimensions, ImageMeta, PathData, Rating, RelativePath, RetagStats, Time},
+    db::{self, PathId, RootData},
+    embedded,
+    tag_source::{TagInput, TagSources},
+    watch_file::{DirEvent, DirWatcher},
+};
+use futures_util::{
+    future::{self, AbortHandle},
+    stream::{Stream, StreamExt},
+};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use futures_util::stream::{Stream, StreamExt};
-use sqlx::SqlitePool;
-use std::{convert::TryFrom, path::PathBuf, sync::Arc, time::Instant};
 use tokio::{
     sync::{
         mpsc::{self, error::TrySendError},
@@ -12,6 +27,7 @@ use tokio::{
         Mutex,
     },
     task,
+    time,
 };
 
 #[derive(derive_more::Deref, Clone)]
@@ -25,18 +41,16 @@ pub struct ScanInner {
 
 enum ScanState {
     Scanning {
-        root: RootData,
         abort_handle: Option<oneshot::Sender<()>>,
     },
     Idle,
 }
 
 impl ScanState {
-    fn scanning(root: RootData) -> (Self, oneshot::Receiver<()>) {
+    fn scanning() -> (Self, oneshot::Receiver<()>) {
         let (tx, rx) = oneshot::channel();
         (
             Self::Scanning {
-                root,
                 abort_handle: Some(tx),
             },
             rx,
@@ -44,7 +58,60 @@ impl ScanState {
     }
 }
 
-fn scan(root: RootData) -> tokio::sync::mpsc::Receiver<(PathBuf, PathData)> {
+enum FileStatus {
+    New,
+    Changed,
+}
+
+fn path_data(root: &RootData, absolute: &Path, stat: &std::fs::Metadata) -> Option<PathData> {
+    let relative =
+        RelativePath::try_from(absolute.strip_prefix(root.path()).ok()?.to_owned()).ok()?;
+    Some(PathData {
+        root_id: root.id(),
+        path: relative,
+        time: Time {
+            mtime: stat.modified().ok()?.into(),
+            btime: stat.created().ok().map(|time| time.into()),
+        },
+    })
+}
+
+/// Sniffs the magic bytes of a file to check if it's in a format that can be shown.
+fn is_image(path: &Path) -> Result<bool, std::io::Error> {
+    let mut header = Vec::with_capacity(16);
+    File::open(path)?.take(16).read_to_end(&mut header)?;
+    Ok(matches!(
+        image::guess_format(&header),
+        Ok(image::ImageFormat::Png)
+            | Ok(image::ImageFormat::Jpeg)
+            | Ok(image::ImageFormat::Gif)
+            | Ok(image::ImageFormat::WebP)
+            | Ok(image::ImageFormat::Bmp)
+    ))
+}
+
+/// Hashes a file and reads its metadata, `None` if it's not an image.
+fn index_file(path: &Path) -> Result<Option<(tgcd::Blake2bHash, ImageMeta)>, std::io::Error> {
+    if !is_image(path)? {
+        return Ok(None);
+    }
+
+    let hash = tgcd::Blake2bHash::from_file(path)?;
+    let dimensions = match Dimensions::from_file(path) {
+        Ok(dimensions) => Some(dimensions),
+        Err(e) => {
+            tracing::debug!("Can't read dimensions of {}: {}", path.display(), e);
+            None
+        }
+    };
+    let rating = embedded::Metadata::read(path)?
+        .rating()
+        .and_then(|rating| Rating::try_from(rating).ok());
+    Ok(Some((hash, ImageMeta { dimensions, rating })))
+}
+
+/// Sends every file under `root`, the flag is false for files that `cfg` excludes.
+fn scan(root: RootData, cfg: ScanConfig) -> tokio::sync::mpsc::Receiver<(PathBuf, PathData, bool)> {
     let (mut tx, rx) = mpsc::channel(1);
     task::spawn_blocking(move || {
         for ent in walkdir::WalkDir::new(root.path())
@@ -52,20 +119,14 @@ fn scan(root: RootData) -> tokio::sync::mpsc::Receiver<(PathBuf, PathData)> {
             .filter_map(|ent| ent.ok())
             .filter(|ent| ent.file_type().is_file())
         {
-            if let Ok(stat) = ent.metadata() {
-                let absolute = ent.into_path();
-                let relative =
-                    RelativePath::try_from(absolute.strip_prefix(root.path()).unwrap().to_owned())
-                        .unwrap();
-                let data = PathData {
-                    root_id: root.id(),
-                    path: relative,
-                    time: Time {
-                        mtime: stat.modified().unwrap().into(),
-                        btime: stat.created().ok().map(|time| time.into()),
-                    },
-                };
-                let mut to_send = (absolute, data);
+            let data = ent.metadata().ok().and_then(|stat| {
+                path_data(&root, ent.path(), &stat).map(|data| {
+                    let accepted = cfg.accepts(data.path.as_ref(), stat.len());
+                    (data, accepted)
+                })
+            });
+            if let Some((data, accepted)) = data {
+                let mut to_send = (ent.into_path(), data, accepted);
 
                 loop {
                     match tx.try_send(to_send) {
@@ -85,6 +146,65 @@ fn scan(root: RootData) -> tokio::sync::mpsc::Receiver<(PathBuf, PathData)> {
     rx
 }
 
+/// Inserts `new_files` and deletes `vanished`.
+/// New files with the same content as a vanished file are treated as moved so they keep
+/// their tags and history.
+async fn apply_changes(
+    cxn: &mut SqliteConnection,
+    root: &RootData,
+    tag_sources: &mut TagSources,
+    new_files: Vec<(PathData, tgcd::Blake2bHash, ImageMeta)>,
+    vanished: Vec<(PathId, Option<ContentHash>)>,
+) -> Result<(), anyhow::Error> {
+    let mut vanished_hashed = HashMap::<_, Vec<_>>::new();
+    let mut vanished_unhashed = Vec::new();
+    for (id, hash) in vanished {
+        match hash {
+            Some(hash) => vanished_hashed.entry(hash).or_default().push(id),
+            None => vanished_unhashed.push(id),
+        }
+    }
+
+    for (path_data, hash, meta) in new_files {
+        let content_hash = ContentHash::from(&hash);
+        // might have been rejected before it was complete
+        db::delete_rejected(cxn, path_data.root_id, &path_data.path).await?;
+        match vanished_hashed.get_mut(&content_hash).and_then(Vec::pop) {
+            Some(id) => {
+                tracing::info!("Detected move to {}", path_data.path.as_ref());
+                db::move_path(cxn, id, &path_data, &content_hash, meta).await?;
+            }
+            None => {
+                let absolute = root.path().join(path_data.path.as_ref());
+                let tags = tag_sources
+                    .tags(&TagInput {
+                        absolute: &absolute,
+                        relative: &path_data.path,
+                        hash: &hash,
+                    })
+                    .await;
+                tracing::info!("Found new file: {}", path_data.path.as_ref());
+                db::insert_new_path(cxn, &path_data, &content_hash, meta, &tags).await?;
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for id in vanished_hashed
+        .into_iter()
+        .flat_map(|(_, ids)| ids)
+        .chain(vanished_unhashed)
+    {
+        db::delete_path(cxn, id).await?;
+        removed += 1;
+    }
+    if removed > 0 {
+        tracing::info!("Removed {} vanished files", removed);
+    }
+
+    Ok(())
+}
+
 struct CpuJobSet<T> {
     tx: Option<tokio::sync::mpsc::Sender<T>>,
 }
@@ -129,7 +249,7 @@ impl ImageScanner {
         }))
     }
 
-    pub fn start_scan(&mut self, pool: &SqlitePool, root: RootData) {
+    pub fn start_scan(&self, pool: &SqlitePool, root: RootData, cfg: ScanConfig) {
         let pool = pool.clone();
         let this = self.0.clone();
         let task = task::spawn(async move {
@@ -138,19 +258,23 @@ impl ImageScanner {
 
                 tracing::info!("Starting scan");
 
-                let (state, mut abort) = ScanState::scanning(root.clone());
+                let (state, mut abort) = ScanState::scanning();
                 {
                     *this.state.lock().await = state;
                 }
 
                 let root_id = root.id();
-                let mut scan = scan(root);
+                let root_path = root.path().to_owned();
+                let mut tag_sources = TagSources::connect(&cfg.tag_sources).await;
+                let mut scan = scan(root.clone(), cfg);
                 let (mut spawner, mut hash_jobs) = CpuJobSet::buffered(32);
-                // FIXME: get this thing from function args
-                let mut tgcd = tgcd::TgcdClient::from_global_config().await.unwrap();
 
                 let mut txn = pool.begin().await.unwrap();
 
+                let mut seen = HashSet::new();
+                let mut rejected = HashSet::new();
+                let mut excluded = HashSet::new();
+                let mut new_files = Vec::new();
                 let mut scan_done = false;
                 let mut loop_done = false;
                 loop {
@@ -161,19 +285,29 @@ impl ImageScanner {
                         }
                         next = scan.next(), if !scan_done => {
                             match next {
-                                Some((absolute, path_data)) => {
-                                    match db::fetch_path_time(&mut txn, root_id, &path_data.path).await? {
-                                        Some(time) if time == path_data.time => (),
-                                        Some(_) => {
-                                            tracing::info!("Updating meta of {}", path_data.path.as_ref());
-                                            db::update_timestamp(&mut txn, &path_data).await?;
-                                        }
-                                        None => {
-                                            spawner.execute(move || -> Result<_, std::io::Error> {
-                                                let hash = tgcd::Blake2bHash::from_file(absolute)?;
-                                                Ok((path_data, hash))
-                                            });
+                                Some((_, path_data, false)) => {
+                                    excluded.insert(path_data.path);
+                                }
+                                Some((absolute, path_data, true)) => {
+                                    seen.insert(path_data.path.clone());
+                                    let status = match db::fetch_path_meta(&mut txn, root_id, &path_data.path).await? {
+                                        Some(meta) if meta.time == path_data.time && meta.indexed => None,
+                                        Some(meta) => {
+                                            if meta.time != path_data.time {
+                                                tracing::info!("Updating meta of {}", path_data.path.as_ref());
+                                            }
+                                            Some(FileStatus::Changed)
                                         }
+                                        None => match db::fetch_rejected(&mut txn, root_id, &path_data.path).await? {
+                                            Some(time) if time == path_data.time => None,
+                                            _ => Some(FileStatus::New),
+                                        },
+                                    };
+                                    if let Some(status) = status {
+                                        spawner.execute(move || match index_file(&absolute) {
+                                            Ok(indexed) => Ok((status, path_data, indexed)),
+                                            Err(e) => Err((absolute, e)),
+                                        });
                                     }
                                 }
                                 None => {
@@ -184,21 +318,64 @@ impl ImageScanner {
                         }
                         job = hash_jobs.next(), if !loop_done => {
                             match job {
-                                Some(Ok((path_data, hash))) => {
-                                    let tags = tgcd.get_tags(&hash).await.unwrap();
-                                    tracing::info!("Found new file: {}", path_data.path.as_ref());
-                                    db::insert_new_path(&mut txn, &path_data, &tags).await?;
+                                Some(Ok((_, path_data, None))) => {
+                                    tracing::debug!("Skipping {}, not an image", path_data.path.as_ref());
+                                    db::insert_rejected(&mut txn, &path_data).await?;
+                                    rejected.insert(path_data.path);
+                                }
+                                Some(Ok((FileStatus::Changed, path_data, Some((hash, meta))))) => {
+                                    db::update_path(&mut txn, &path_data, &ContentHash::from(&hash), meta).await?;
+                                }
+                                Some(Ok((FileStatus::New, path_data, Some((hash, meta))))) => {
+                                    new_files.push((path_data, hash, meta));
+                                }
+                                Some(Err((absolute, e))) => {
+                                    tracing::warn!("Can't hash {}: {}", absolute.display(), e);
                                 }
                                 None => {
                                     loop_done = true;
                                 }
-                                _ => (),
                             }
                         }
                         else => break,
                     }
                 }
 
+                // don't throw away the whole index just because the directory isn't mounted
+                let mut vanished = Vec::new();
+                if root_path.is_dir() {
+                    let was_excluded = db::fetch_excluded_paths(&mut txn, root_id)
+                        .await?
+                        .into_iter()
+                        .collect::<HashSet<_>>();
+                    for (id, path, hash) in db::fetch_root_paths(&mut txn, root_id).await? {
+                        if excluded.contains(&path) {
+                            // keep tags and history in case the scan config changes back
+                            if !was_excluded.contains(&path) {
+                                tracing::info!("Excluding {}", path.as_ref());
+                                db::set_excluded(&mut txn, id, true).await?;
+                            }
+                        } else if !seen.contains(&path) || rejected.contains(&path) {
+                            vanished.push((id, hash));
+                        } else if was_excluded.contains(&path) {
+                            tracing::info!("Including {} again", path.as_ref());
+                            db::set_excluded(&mut txn, id, false).await?;
+                        }
+                    }
+                    for path in db::fetch_rejected_paths(&mut txn, root_id).await? {
+                        if !seen.contains(&path) && !excluded.contains(&path) {
+                            db::delete_rejected(&mut txn, root_id, &path).await?;
+                        }
+                    }
+                } else {
+                    tracing::warn!(
+                        "{} is not available, keeping vanished files",
+                        root_path.display()
+                    );
+                }
+
+                apply_changes(&mut txn, &root, &mut tag_sources, new_files, vanished).await?;
+
                 tracing::info!(
                     duration = %humantime::Duration::from(Instant::now().duration_since(scan_begin)),
                     "Finished scan",
@@ -220,18 +397,321 @@ impl ImageScanner {
         });
     }
 
-    pub async fn abort_if_root_differs(&mut self, root_id: RootId) {
-        let mut state = self.0.state.lock().await;
-        match *state {
-            ScanState::Scanning {
-                ref mut abort_handle,
-                ref root,
-            } => {
-                if root.id() == root_id {
-                    let _ = abort_handle.take().and_then(|handle| handle.send(()).ok());
+    /// Applies changes in the root directory to the database as they happen.
+    /// Stops when the returned `LiveIndex` gets dropped.
+    pub fn start_live_index(
+        &self,
+        pool: &SqlitePool,
+        root: RootData,
+        cfg: ScanConfig,
+    ) -> Result<LiveIndex, std::io::Error> {
+        let (watch_task, events) = DirWatcher::default().watch(root.path())?;
+        let watch_task = async move {
+            if let Err(e) = watch_task.await {
+                tracing::error!("Can't watch wallpaper directory: {}", e);
+            }
+        };
+        let (task, handle) = future::abortable(future::join(
+            watch_task,
+            self.clone().index_events(pool.clone(), root, cfg, events),
+        ));
+        task::spawn(task);
+
+        Ok(LiveIndex(handle))
+    }
+
+    async fn index_events(
+        self,
+        pool: SqlitePool,
+        root: RootData,
+        cfg: ScanConfig,
+        mut events: impl Stream<Item = DirEvent> + Unpin,
+    ) {
+        while let Some(ev) = events.next().await {
+            let mut batch = vec![ev];
+            // collect everything that happens shortly after so moves can be paired up
+            while let Ok(Some(ev)) = time::timeout(Duration::from_millis(500), events.next()).await
+            {
+                batch.push(ev);
+            }
+
+            if batch.iter().any(|ev| matches!(ev, DirEvent::Overflow)) {
+                tracing::info!("Lost track of changes in wallpaper directory, rescanning");
+                self.start_scan(&pool, root.clone(), cfg.clone());
+                continue;
+            }
+
+            if let Err(e) = self.index_batch(&pool, &root, &cfg, batch).await {
+                tracing::error!("{}", e);
+            }
+        }
+    }
+
+    async fn index_batch(
+        &self,
+        pool: &SqlitePool,
+        root: &RootData,
+        cfg: &ScanConfig,
+        batch: Vec<DirEvent>,
+    ) -> Result<(), anyhow::Error> {
+        let _scanning = self.scanning.lock().await;
+
+        let walk_root = root.clone();
+        let walk_cfg = cfg.clone();
+        // events might cancel each other out so only look at what's on disk right now
+        let (changed, rejected, excluded, removed) = task::spawn_blocking(move || {
+            let mut changed = Vec::new();
+            let mut rejected = Vec::new();
+            let mut excluded = Vec::new();
+            let mut removed = Vec::new();
+            let mut seen = HashSet::new();
+            for ev in batch {
+                let path = match ev {
+                    DirEvent::Changed(path) | DirEvent::Removed(path) => path,
+                    DirEvent::Overflow => continue,
                 };
+                if !seen.insert(path.clone()) {
+                    continue;
+                }
+
+                if path.exists() {
+                    for ent in walkdir::WalkDir::new(&path)
+                        .into_iter()
+                        .filter_map(|ent| ent.ok())
+                        .filter(|ent| ent.file_type().is_file())
+                    {
+                        let data = ent.metadata().ok().and_then(|stat| {
+                            path_data(&walk_root, ent.path(), &stat).map(|data| {
+                                let accepted = walk_cfg.accepts(data.path.as_ref(), stat.len());
+                                (data, accepted)
+                            })
+                        });
+                        match data {
+                            Some((data, false)) => excluded.push(data.path),
+                            Some((data, true)) => match index_file(ent.path()) {
+                                Ok(Some((hash, meta))) => changed.push((data, hash, meta)),
+                                Ok(None) => rejected.push(data),
+                                Err(e) => {
+                                    tracing::warn!("Can't hash {}: {}", ent.path().display(), e)
+                                }
+                            },
+                            None => (),
+                        }
+                    }
+                } else if let Some(relative) = path
+                    .strip_prefix(walk_root.path())
+                    .ok()
+                    .and_then(|path| RelativePath::try_from(path.to_owned()).ok())
+                {
+                    removed.push(relative);
+                }
             }
-            _ => (),
+            (changed, rejected, excluded, removed)
+        })
+        .await
+        .unwrap();
+
+        let mut txn = pool.begin().await?;
+
+        let mut new_files = Vec::new();
+        for (path_data, hash, meta) in changed {
+            match db::fetch_path_meta(&mut txn, root.id(), &path_data.path).await? {
+                Some(meta) if meta.time == path_data.time && meta.indexed => (),
+                Some(_) => {
+                    tracing::info!("Updating meta of {}", path_data.path.as_ref());
+                    let hash = ContentHash::from(&hash);
+                    db::update_path(&mut txn, &path_data, &hash, meta).await?;
+                }
+                None => new_files.push((path_data, hash, meta)),
+            }
+        }
+
+        let mut vanished = Vec::new();
+        for path_data in rejected {
+            tracing::debug!("Skipping {}, not an image", path_data.path.as_ref());
+            db::insert_rejected(&mut txn, &path_data).await?;
+            for (id, _, hash) in db::fetch_paths_under(&mut txn, root.id(), &path_data.path).await?
+            {
+                vanished.push((id, hash));
+            }
+        }
+        for path in excluded {
+            for (id, _, _) in db::fetch_paths_under(&mut txn, root.id(), &path).await? {
+                tracing::info!("Excluding {}", path.as_ref());
+                db::set_excluded(&mut txn, id, true).await?;
+            }
+        }
+        for path in removed {
+            db::delete_rejected(&mut txn, root.id(), &path).await?;
+            for (id, _, hash) in db::fetch_paths_under(&mut txn, root.id(), &path).await? {
+                vanished.push((id, hash));
+            }
+        }
+
+        if !new_files.is_empty() || !vanished.is_empty() {
+            let mut tag_sources = TagSources::connect(&cfg.tag_sources).await;
+            apply_changes(&mut txn, root, &mut tag_sources, new_files, vanished).await?;
+        }
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Handle to work on files of `root` from outside of the control loop.
+    pub fn handle(&self, root: RootData, cfg: ScanConfig) -> RootHandle {
+        RootHandle {
+            scanner: self.clone(),
+            root,
+            cfg,
+        }
+    }
+
+    /// Aborts the running scan without committing anything.
+    pub async fn abort(&self) {
+        if let ScanState::Scanning {
+            ref mut abort_handle,
+        } = *self.0.state.lock().await
+        {
+            let _ = abort_handle.take().and_then(|handle| handle.send(()).ok());
         }
     }
 }
+
+#[derive(Clone)]
+pub struct RootHandle {
+    scanner: ImageScanner,
+    root: RootData,
+    cfg: ScanConfig,
+}
+
+impl std::fmt::Debug for RootHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RootHandle")
+            .field("root", &self.root)
+            .field("cfg", &self.cfg)
+            .finish()
+    }
+}
+
+impl RootHandle {
+    pub fn root(&self) -> &RootData {
+        &self.root
+    }
+
+    /// Indexes `path` unless it already is and returns its id.
+    pub async fn index_path(
+        &self,
+        pool: &SqlitePool,
+        path: &RelativePath,
+    ) -> Result<PathId, anyhow::Error> {
+        let mut cxn = pool.acquire().await?;
+        if let Some(id) = db::fetch_path_id(&mut cxn, self.root.id(), path).await? {
+            return Ok(id);
+        }
+        drop(cxn);
+
+        let absolute = self.root.path().join(path.as_ref());
+        if !absolute.is_file() {
+            anyhow::bail!("{} is not a file", absolute.display());
+        }
+        self.scanner
+            .index_batch(
+                pool,
+                &self.root,
+                &self.cfg,
+                vec![DirEvent::Changed(absolute)],
+            )
+            .await?;
+
+        let mut cxn = pool.acquire().await?;
+        match db::fetch_path_id(&mut cxn, self.root.id(), path).await? {
+            Some(id) => Ok(id),
+            None => anyhow::bail!(
+                "{} is not an image or excluded from scanning",
+                path.as_ref()
+            ),
+        }
+    }
+
+    /// Queries tags of already indexed files again and updates the stored ones.
+    /// Retags everything below `paths` or the whole root if `None`.
+    pub async fn retag(
+        &self,
+        pool: &SqlitePool,
+        paths: Option<Vec<RelativePath>>,
+    ) -> Result<RetagStats, anyhow::Error> {
+        let _scanning = self.scanner.scanning.lock().await;
+        // removing tags only because a source is down would be bad
+        let mut tag_sources = TagSources::connect_all(&self.cfg.tag_sources).await?;
+        let root_id = self.root.id();
+
+        let mut txn = pool.begin().await?;
+        let files = match paths {
+            Some(paths) => {
+                let mut files = Vec::new();
+                for path in paths {
+                    files.extend(db::fetch_paths_under(&mut txn, root_id, &path).await?);
+                }
+                files
+            }
+            None => db::fetch_root_paths(&mut txn, root_id).await?,
+        };
+
+        let mut stats = RetagStats::default();
+        for (id, path, hash) in files {
+            // not indexed yet, gets tagged when it is
+            let hash = match hash.and_then(|hash| hash.to_blake2b()) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let absolute = self.root.path().join(path.as_ref());
+            let input = TagInput {
+                absolute: &absolute,
+                relative: &path,
+                hash: &hash,
+            };
+            let tags = match tag_sources.all_tags(&input).await {
+                Ok(tags) => tags,
+                Err(e) => {
+                    tracing::warn!("Not retagging {}: {}", path.as_ref(), e);
+                    continue;
+                }
+            };
+
+            let (added, removed) = db::set_path_tags(&mut txn, id, &tags).await?;
+            stats.files += 1;
+            stats.added += added;
+            stats.removed += removed;
+        }
+
+        txn.commit().await?;
+
+        tracing::info!(
+            files = stats.files,
+            added = stats.added,
+            removed = stats.removed,
+            "Retagged files"
+        );
+
+        Ok(stats)
+    }
+
+    pub fn start_retag(&self, pool: &SqlitePool) {
+        let this = self.clone();
+        let pool = pool.clone();
+        task::spawn(async move {
+            if let Err(e) = this.retag(&pool, None).await {
+                tracing::error!("Can't retag: {}", e);
+            }
+        });
+    }
+}
+
+pub struct LiveIndex(AbortHandle);
+
+impl Drop for LiveIndex {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
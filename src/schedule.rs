@@ -0,0 +1,415 @@
+use futures_util::future::{BoxFuture, FutureExt};
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+use time::{Date, OffsetDateTime, UtcOffset};
+
+mod solar;
+
+pub use solar::SolarEvent;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const SECONDS_PER_DAY: u32 = MINUTES_PER_DAY * 60;
+
+/// Source of the current local time so transitions don't depend on the system clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> OffsetDateTime;
+
+    /// Completes once `duration` has passed.
+    fn delay_for(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc().to_offset(UtcOffset::current_local_offset())
+    }
+
+    fn delay_for(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::delay_for(duration).boxed()
+    }
+}
+
+/// Where on earth solar events in schedules are computed for.
+#[derive(Deserialize, Copy, Clone, Debug)]
+pub struct Location {
+    /// Degrees north
+    pub latitude: f64,
+    /// Degrees east
+    pub longitude: f64,
+}
+
+impl Location {
+    pub fn is_valid(&self) -> bool {
+        (-90. ..=90.).contains(&self.latitude) && (-180. ..=180.).contains(&self.longitude)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum TimeOfDay {
+    /// Minutes since midnight
+    Clock(u32),
+    Solar(SolarEvent),
+}
+
+impl TimeOfDay {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        SolarEvent::parse(s)
+            .map(TimeOfDay::Solar)
+            .or_else(|| parse_clock_time(s).map(TimeOfDay::Clock))
+    }
+
+    /// Minutes since midnight on `date`, solar events need a location.
+    fn resolve(self, date: Date, offset: UtcOffset, location: Option<Location>) -> Option<u32> {
+        match self {
+            TimeOfDay::Clock(minute) => Some(minute),
+            TimeOfDay::Solar(event) => {
+                location.map(|location| solar::minute_of_day(event, date, offset, location))
+            }
+        }
+    }
+}
+
+/// A time of day window like `07:00-18:00` or `sunset-dusk`, wraps around midnight if the end
+/// is before the start. Clock times cover the whole day if both are the same.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: TimeOfDay,
+    end: TimeOfDay,
+}
+
+impl TimeWindow {
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(2, '-');
+        let start = TimeOfDay::parse(parts.next()?)?;
+        let end = TimeOfDay::parse(parts.next()?)?;
+        Some(Self { start, end })
+    }
+
+    /// Whether this window depends on the sun.
+    pub fn is_solar(&self) -> bool {
+        [self.start, self.end]
+            .iter()
+            .any(|time| matches!(time, TimeOfDay::Solar(_)))
+    }
+
+    /// Start and end in minutes since midnight on the day of `now`.
+    fn resolve(&self, now: OffsetDateTime, location: Option<Location>) -> Option<(u32, u32)> {
+        let start = self.start.resolve(now.date(), now.offset(), location)?;
+        let end = self.end.resolve(now.date(), now.offset(), location)?;
+        Some((start, end))
+    }
+
+    /// Whether `now` is inside of this window on one of `days`.
+    /// The part after midnight of a wrapping window belongs to the day it started on.
+    pub fn contains(&self, days: Days, now: OffsetDateTime, location: Option<Location>) -> bool {
+        let (start, end) = match self.resolve(now, location) {
+            Some(window) => window,
+            None => return false,
+        };
+        let minute = u32::from(now.hour()) * 60 + u32::from(now.minute());
+        let today = now.weekday().number_days_from_monday();
+        let yesterday = (today + 6) % 7;
+        if start == end {
+            // solar events only meet when the sun doesn't rise or set
+            !self.is_solar() && days.contains(today)
+        } else if start < end {
+            (start..end).contains(&minute) && days.contains(today)
+        } else if minute >= start {
+            days.contains(today)
+        } else {
+            minute < end && days.contains(yesterday)
+        }
+    }
+
+    fn boundaries(&self, now: OffsetDateTime, location: Option<Location>) -> Vec<u32> {
+        self.resolve(now, location)
+            .map(|(start, end)| vec![start, end])
+            .unwrap_or_default()
+    }
+}
+
+/// Parses `HH:MM` to minutes since midnight, `24:00` is midnight.
+fn parse_clock_time(s: &str) -> Option<u32> {
+    let mut parts = s.splitn(2, ':');
+    let hour = parts.next()?.parse::<u32>().ok()?;
+    let minute = parts.next()?.parse::<u32>().ok()?;
+    match (hour, minute) {
+        (24, 0) => Some(0),
+        (0..=23, 0..=59) => Some(hour * 60 + minute),
+        _ => None,
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeWindow {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(de)?;
+        Self::parse(&s).ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "Invalid time window {:?}, expected something like \"07:00-18:00\" or \"sunrise-sunset\"",
+                s
+            ))
+        })
+    }
+}
+
+/// A set of weekdays.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Days(u8);
+
+impl Default for Days {
+    fn default() -> Self {
+        Days(0b111_1111)
+    }
+}
+
+impl Days {
+    /// `day` is the number of days from monday.
+    fn contains(self, day: u8) -> bool {
+        self.0 & (1 << day) != 0
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Some(Days(match s {
+            "mon" => 1,
+            "tue" => 1 << 1,
+            "wed" => 1 << 2,
+            "thu" => 1 << 3,
+            "fri" => 1 << 4,
+            "sat" => 1 << 5,
+            "sun" => 1 << 6,
+            "weekdays" => 0b001_1111,
+            "weekends" => 0b110_0000,
+            "daily" => 0b111_1111,
+            _ => return None,
+        }))
+    }
+}
+
+impl<'de> Deserialize<'de> for Days {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        let names = match Repr::deserialize(de)? {
+            Repr::One(name) => vec![name],
+            Repr::Many(names) => names,
+        };
+        let mut days = 0;
+        for name in names {
+            let day = Days::parse(&name.to_lowercase()).ok_or_else(|| {
+                serde::de::Error::custom(format!(
+                    "Invalid day {:?}, expected \"mon\" to \"sun\", \"weekdays\", \"weekends\" or \"daily\"",
+                    name
+                ))
+            })?;
+            days |= day.0;
+        }
+
+        Ok(if days == 0 {
+            Days::default()
+        } else {
+            Days(days)
+        })
+    }
+}
+
+/// Time from `now` until the next start or end of any of `windows`. Solar events are taken
+/// from today, so a transition tomorrow can be off by a minute and gets rechecked.
+pub fn until_next_boundary<'a>(
+    windows: impl IntoIterator<Item = &'a TimeWindow>,
+    now: OffsetDateTime,
+    location: Option<Location>,
+) -> Option<Duration> {
+    let secs =
+        u32::from(now.hour()) * 3600 + u32::from(now.minute()) * 60 + u32::from(now.second());
+    windows
+        .into_iter()
+        .flat_map(|window| window.boundaries(now, location))
+        .map(
+            |boundary| match (boundary * 60 + SECONDS_PER_DAY - secs) % SECONDS_PER_DAY {
+                // right now, so the next one is tomorrow
+                0 => SECONDS_PER_DAY,
+                secs => secs,
+            },
+        )
+        .min()
+        .map(|secs| Duration::from_secs(u64::from(secs)))
+}
+
+/// Completes when the schedule entry might change next, after a day if nothing is scheduled.
+pub fn next_transition<'a>(
+    clock: &dyn Clock,
+    windows: impl IntoIterator<Item = &'a TimeWindow>,
+    location: Option<Location>,
+) -> BoxFuture<'static, ()> {
+    let until = until_next_boundary(windows, clock.now(), location)
+        .unwrap_or_else(|| Duration::from_secs(u64::from(SECONDS_PER_DAY)));
+    clock.delay_for(until)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future;
+    use std::sync::Mutex;
+
+    /// 2020-11-02 is a monday.
+    fn at(day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        Date::try_from_ymd(2020, 11, day)
+            .unwrap()
+            .try_with_hms(hour, minute, 0)
+            .unwrap()
+            .assume_utc()
+    }
+
+    fn window(s: &str) -> TimeWindow {
+        TimeWindow::parse(s).unwrap()
+    }
+
+    fn contains(window_src: &str, days: Days, now: OffsetDateTime) -> bool {
+        window(window_src).contains(days, now, None)
+    }
+
+    #[test]
+    fn contains_clock_window() {
+        let daily = Days::default();
+        assert!(!contains("07:00-18:00", daily, at(2, 6, 59)));
+        assert!(contains("07:00-18:00", daily, at(2, 7, 0)));
+        assert!(contains("07:00-18:00", daily, at(2, 17, 59)));
+        assert!(!contains("07:00-18:00", daily, at(2, 18, 0)));
+    }
+
+    #[test]
+    fn contains_wrapping_window() {
+        let daily = Days::default();
+        assert!(!contains("22:00-06:00", daily, at(2, 21, 59)));
+        assert!(contains("22:00-06:00", daily, at(2, 22, 0)));
+        assert!(contains("22:00-06:00", daily, at(2, 23, 59)));
+        assert!(contains("22:00-06:00", daily, at(3, 0, 0)));
+        assert!(contains("22:00-06:00", daily, at(3, 5, 59)));
+        assert!(!contains("22:00-06:00", daily, at(3, 6, 0)));
+        assert!(!contains("22:00-06:00", daily, at(3, 12, 0)));
+    }
+
+    #[test]
+    fn wrapping_window_belongs_to_start_day() {
+        let weekdays = Days::parse("weekdays").unwrap();
+        // friday night
+        assert!(contains("22:00-06:00", weekdays, at(6, 23, 0)));
+        assert!(contains("22:00-06:00", weekdays, at(7, 3, 0)));
+        // saturday night
+        assert!(!contains("22:00-06:00", weekdays, at(7, 23, 0)));
+        // sunday night
+        assert!(!contains("22:00-06:00", weekdays, at(9, 3, 0)));
+        assert!(contains("22:00-06:00", weekdays, at(9, 23, 0)));
+    }
+
+    #[test]
+    fn contains_whole_day() {
+        let sat = Days::parse("sat").unwrap();
+        assert!(contains("00:00-24:00", sat, at(7, 0, 0)));
+        assert!(contains("00:00-24:00", sat, at(7, 23, 59)));
+        assert!(!contains("00:00-24:00", sat, at(8, 12, 0)));
+    }
+
+    #[test]
+    fn solar_window_needs_location() {
+        assert!(!contains("sunrise-sunset", Days::default(), at(2, 12, 0)));
+        assert_eq!(
+            until_next_boundary(&[window("sunrise-sunset")], at(2, 12, 0), None),
+            None
+        );
+    }
+
+    fn hours(hours: u64, minutes: u64) -> Option<Duration> {
+        Some(Duration::from_secs(hours * 3600 + minutes * 60))
+    }
+
+    #[test]
+    fn next_boundary() {
+        let windows = [window("07:00-18:00")];
+        assert_eq!(
+            until_next_boundary(&windows, at(2, 5, 0), None),
+            hours(2, 0)
+        );
+        assert_eq!(
+            until_next_boundary(&windows, at(2, 12, 30), None),
+            hours(5, 30)
+        );
+        // a boundary right now is the one tomorrow
+        assert_eq!(
+            until_next_boundary(&windows, at(2, 18, 0), None),
+            hours(13, 0)
+        );
+        let none: &[TimeWindow] = &[];
+        assert_eq!(until_next_boundary(none, at(2, 18, 0), None), None);
+    }
+
+    #[test]
+    fn next_boundary_wrapping() {
+        let windows = [window("22:00-06:00"), window("12:00-13:00")];
+        assert_eq!(
+            until_next_boundary(&windows, at(2, 23, 30), None),
+            hours(6, 30)
+        );
+        assert_eq!(
+            until_next_boundary(&windows, at(3, 3, 0), None),
+            hours(3, 0)
+        );
+        assert_eq!(
+            until_next_boundary(&windows, at(3, 12, 15), None),
+            hours(0, 45)
+        );
+        assert_eq!(
+            until_next_boundary(
+                &windows,
+                at(3, 5, 0) + Duration::from_secs(59 * 60 + 30),
+                None
+            ),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    struct FakeClock {
+        now: OffsetDateTime,
+        delays: Mutex<Vec<Duration>>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> OffsetDateTime {
+            self.now
+        }
+
+        fn delay_for(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.delays.lock().unwrap().push(duration);
+            future::ready(()).boxed()
+        }
+    }
+
+    #[test]
+    fn transition_waits_on_clock() {
+        let clock = FakeClock {
+            now: at(2, 21, 0),
+            delays: Mutex::default(),
+        };
+        let none: &[TimeWindow] = &[];
+        drop(next_transition(&clock, &[window("22:00-06:00")], None));
+        drop(next_transition(&clock, none, None));
+        assert_eq!(
+            *clock.delays.lock().unwrap(),
+            vec![Duration::from_secs(3600), Duration::from_secs(24 * 60 * 60)]
+        );
+    }
+}
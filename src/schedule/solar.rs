@@ -0,0 +1,230 @@
+use super::{Location, MINUTES_PER_DAY};
+use time::{Date, UtcOffset};
+
+/// Julian day of 2000-01-01 12:00 UTC
+const J2000: f64 = 2_451_545.;
+/// Tilt of the earth's axis in degrees
+const OBLIQUITY: f64 = 23.4397;
+
+/// Where the sun is relative to the horizon, dawn and dusk are the start and end of civil
+/// twilight.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SolarEvent {
+    Dawn,
+    Sunrise,
+    Sunset,
+    Dusk,
+}
+
+impl SolarEvent {
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "dawn" => SolarEvent::Dawn,
+            "sunrise" => SolarEvent::Sunrise,
+            "sunset" => SolarEvent::Sunset,
+            "dusk" => SolarEvent::Dusk,
+            _ => return None,
+        })
+    }
+
+    /// Altitude of the sun's center in degrees, sunrise and sunset account for refraction and
+    /// the size of the sun.
+    fn altitude(self) -> f64 {
+        match self {
+            SolarEvent::Dawn | SolarEvent::Dusk => -6.,
+            SolarEvent::Sunrise | SolarEvent::Sunset => -0.833,
+        }
+    }
+
+    fn is_rising(self) -> bool {
+        match self {
+            SolarEvent::Dawn | SolarEvent::Sunrise => true,
+            SolarEvent::Sunset | SolarEvent::Dusk => false,
+        }
+    }
+}
+
+/// Minutes since midnight at `offset` when `event` happens on `date` at `location`, accurate to
+/// a minute or two.
+///
+/// If the sun doesn't cross the event's altitude on that day rising events are moved to the end
+/// of the day and setting events to the start while the sun stays below it, and the other way
+/// around while it stays above, so a window between them is either empty or the whole day.
+pub fn minute_of_day(event: SolarEvent, date: Date, offset: UtcOffset, location: Location) -> u32 {
+    // https://en.wikipedia.org/wiki/Sunrise_equation
+    let latitude = location.latitude.to_radians();
+    let mean_noon = (date.julian_day() as f64 - J2000) - location.longitude / 360.;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_noon)
+        .rem_euclid(360.)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2. * anomaly).sin() + 0.0003 * (3. * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180. + 102.9372)
+        .rem_euclid(360.)
+        .to_radians();
+    let transit =
+        J2000 + mean_noon + 0.0053 * anomaly.sin() - 0.0069 * (2. * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * OBLIQUITY.to_radians().sin()).asin();
+    let cos_hour_angle = (event.altitude().to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+
+    if cos_hour_angle > 1. {
+        // always below
+        return if event.is_rising() {
+            MINUTES_PER_DAY
+        } else {
+            0
+        };
+    } else if cos_hour_angle < -1. {
+        // always above
+        return if event.is_rising() {
+            0
+        } else {
+            MINUTES_PER_DAY
+        };
+    }
+
+    let hour_angle = cos_hour_angle.acos().to_degrees() / 360.;
+    let julian = if event.is_rising() {
+        transit - hour_angle
+    } else {
+        transit + hour_angle
+    };
+    // julian days start at noon
+    let utc_minute = ((julian + 0.5).fract() * f64::from(MINUTES_PER_DAY)).round() as i64;
+    (utc_minute + i64::from(offset.as_seconds() / 60)).rem_euclid(i64::from(MINUTES_PER_DAY)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::{until_next_boundary, Days, TimeWindow};
+
+    const LONDON: Location = Location {
+        latitude: 51.5074,
+        longitude: -0.1278,
+    };
+    const SYDNEY: Location = Location {
+        latitude: -33.8688,
+        longitude: 151.2093,
+    };
+    const TROMSO: Location = Location {
+        latitude: 69.6492,
+        longitude: 18.9553,
+    };
+
+    fn date(month: u8, day: u8) -> Date {
+        Date::try_from_ymd(2020, month, day).unwrap()
+    }
+
+    /// Asserts `event` is within 3 minutes of `hour:minute`.
+    fn assert_near(
+        event: SolarEvent,
+        date: Date,
+        offset: i8,
+        location: Location,
+        (hour, minute): (u32, u32),
+    ) {
+        let actual = minute_of_day(event, date, UtcOffset::hours(offset), location);
+        let expected = hour * 60 + minute;
+        assert!(
+            (i64::from(actual) - i64::from(expected)).abs() <= 3,
+            "{:?} on {} at {:?}: expected {}, got {}",
+            event,
+            date,
+            location,
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn reference_values() {
+        let summer = date(6, 21);
+        let winter = date(12, 21);
+        assert_near(SolarEvent::Sunrise, summer, 1, LONDON, (4, 43));
+        assert_near(SolarEvent::Sunset, summer, 1, LONDON, (21, 21));
+        assert_near(SolarEvent::Sunrise, winter, 0, LONDON, (8, 4));
+        assert_near(SolarEvent::Sunset, winter, 0, LONDON, (15, 53));
+        assert_near(SolarEvent::Dawn, winter, 0, LONDON, (7, 25));
+        assert_near(SolarEvent::Dusk, winter, 0, LONDON, (16, 33));
+        assert_near(SolarEvent::Sunrise, winter, 11, SYDNEY, (5, 41));
+        assert_near(SolarEvent::Sunset, winter, 11, SYDNEY, (20, 5));
+    }
+
+    #[test]
+    fn polar_day() {
+        let offset = UtcOffset::hours(2);
+        let summer = date(6, 21);
+        // not shifted by the offset
+        assert_eq!(
+            minute_of_day(SolarEvent::Sunrise, summer, offset, TROMSO),
+            0
+        );
+        assert_eq!(
+            minute_of_day(SolarEvent::Sunset, summer, offset, TROMSO),
+            MINUTES_PER_DAY
+        );
+
+        let window = TimeWindow::parse("sunrise-sunset").unwrap();
+        for hour in 0..24 {
+            let now = summer
+                .try_with_hms(hour, 30, 0)
+                .unwrap()
+                .assume_offset(offset);
+            assert!(window.contains(Days::default(), now, Some(TROMSO)));
+        }
+    }
+
+    #[test]
+    fn polar_night() {
+        let offset = UtcOffset::hours(1);
+        let winter = date(12, 21);
+        assert_eq!(
+            minute_of_day(SolarEvent::Sunrise, winter, offset, TROMSO),
+            MINUTES_PER_DAY
+        );
+        assert_eq!(minute_of_day(SolarEvent::Sunset, winter, offset, TROMSO), 0);
+        // there's still twilight
+        let dawn = minute_of_day(SolarEvent::Dawn, winter, offset, TROMSO);
+        let dusk = minute_of_day(SolarEvent::Dusk, winter, offset, TROMSO);
+        assert!(dawn < dusk && dusk < MINUTES_PER_DAY);
+
+        let window = TimeWindow::parse("sunrise-sunset").unwrap();
+        for hour in 0..24 {
+            let now = winter
+                .try_with_hms(hour, 30, 0)
+                .unwrap()
+                .assume_offset(offset);
+            assert!(!window.contains(Days::default(), now, Some(TROMSO)));
+            assert!(
+                until_next_boundary(&[window], now, Some(TROMSO)).unwrap()
+                    <= std::time::Duration::from_secs(24 * 60 * 60)
+            );
+        }
+    }
+
+    #[test]
+    fn poles() {
+        let north = Location {
+            latitude: 90.,
+            longitude: 0.,
+        };
+        let south = Location {
+            latitude: -90.,
+            longitude: 0.,
+        };
+        let offset = UtcOffset::UTC;
+        let winter = date(12, 21);
+        assert_eq!(
+            minute_of_day(SolarEvent::Dawn, winter, offset, north),
+            MINUTES_PER_DAY
+        );
+        assert_eq!(minute_of_day(SolarEvent::Dusk, winter, offset, north), 0);
+        assert_eq!(minute_of_day(SolarEvent::Sunrise, winter, offset, south), 0);
+        assert_eq!(
+            minute_of_day(SolarEvent::Sunset, winter, offset, south),
+            MINUTES_PER_DAY
+        );
+    }
+}
@@ -0,0 +1,491 @@
+use serde::{Deserialize, Deserializer};
+use std::{iter::Peekable, str::CharIndices};
+
+/// A boolean expression over tag names like `(nature or space) and not nsfw`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Box<TagExpr>, Box<TagExpr>),
+    Or(Box<TagExpr>, Box<TagExpr>),
+}
+
+#[derive(snafu::Snafu, Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    #[snafu(display("Unexpected end of tag expression"))]
+    UnexpectedEnd,
+
+    #[snafu(display("Unexpected {} at position {} in tag expression", token, pos))]
+    UnexpectedToken { token: String, pos: usize },
+
+    #[snafu(display("Unterminated quote at position {} in tag expression", pos))]
+    UnterminatedQuote { pos: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Tag(String),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::LParen => f.write_str("'('"),
+            Token::RParen => f.write_str("')'"),
+            Token::And => f.write_str("'and'"),
+            Token::Or => f.write_str("'or'"),
+            Token::Not => f.write_str("'not'"),
+            Token::Tag(name) => write!(f, "tag {:?}", name),
+        }
+    }
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<(usize, Token), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((_, c)) = self.chars.peek() {
+            if c.is_whitespace() {
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let (start, c) = self.chars.next()?;
+        let token = match c {
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '"' => {
+                let mut name = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => name.push(c),
+                        None => return Some(Err(ParseError::UnterminatedQuote { pos: start })),
+                    }
+                }
+                Token::Tag(name)
+            }
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = self.chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    self.chars.next();
+                }
+                match &self.src[start..end] {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    name => Token::Tag(name.to_owned()),
+                }
+            }
+        };
+
+        Some(Ok((start, token)))
+    }
+}
+
+struct Parser<'a> {
+    tokens: Peekable<Lexer<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Result<Option<&Token>, ParseError> {
+        match self.tokens.peek() {
+            Some(Ok((_, token))) => Ok(Some(token)),
+            Some(Err(e)) => Err(e.clone()),
+            None => Ok(None),
+        }
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), ParseError> {
+        self.tokens.next().unwrap_or(Err(ParseError::UnexpectedEnd))
+    }
+
+    // or := and ("or" and)*
+    fn or(&mut self) -> Result<TagExpr, ParseError> {
+        let mut lhs = self.and()?;
+        while self.peek()? == Some(&Token::Or) {
+            self.next()?;
+            lhs = TagExpr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    // and := not ("and" not)*
+    fn and(&mut self) -> Result<TagExpr, ParseError> {
+        let mut lhs = self.not()?;
+        while self.peek()? == Some(&Token::And) {
+            self.next()?;
+            lhs = TagExpr::And(Box::new(lhs), Box::new(self.not()?));
+        }
+        Ok(lhs)
+    }
+
+    // not := "not" not | "(" or ")" | tag
+    fn not(&mut self) -> Result<TagExpr, ParseError> {
+        match self.next()? {
+            (_, Token::Not) => Ok(TagExpr::Not(Box::new(self.not()?))),
+            (_, Token::LParen) => {
+                let ret = self.or()?;
+                match self.next()? {
+                    (_, Token::RParen) => Ok(ret),
+                    (pos, token) => Err(unexpected(pos, token)),
+                }
+            }
+            (_, Token::Tag(name)) => Ok(TagExpr::Tag(name)),
+            (pos, token) => Err(unexpected(pos, token)),
+        }
+    }
+}
+
+fn unexpected(pos: usize, token: Token) -> ParseError {
+    ParseError::UnexpectedToken {
+        token: token.to_string(),
+        pos,
+    }
+}
+
+impl TagExpr {
+    pub fn parse(src: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            tokens: Lexer {
+                src,
+                chars: src.char_indices().peekable(),
+            }
+            .peekable(),
+        };
+        let ret = parser.or()?;
+        match parser.tokens.next() {
+            None => Ok(ret),
+            Some(Ok((pos, token))) => Err(unexpected(pos, token)),
+            Some(Err(e)) => Err(e),
+        }
+    }
+
+    /// Requires every tag in `tags`.
+    pub fn all(tags: Vec<String>) -> Option<Self> {
+        tags.into_iter()
+            .map(TagExpr::Tag)
+            .fold(None, |acc, tag| match acc {
+                Some(acc) => Some(TagExpr::And(Box::new(acc), Box::new(tag))),
+                None => Some(tag),
+            })
+    }
+
+    /// All tag names used in this expression.
+    pub fn tags(&self) -> Vec<&str> {
+        let mut ret = Vec::new();
+        self.collect_tags(&mut ret);
+        ret.sort_unstable();
+        ret.dedup();
+        ret
+    }
+
+    fn collect_tags<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            TagExpr::Tag(name) => out.push(name),
+            TagExpr::Not(expr) => expr.collect_tags(out),
+            TagExpr::And(lhs, rhs) | TagExpr::Or(lhs, rhs) => {
+                lhs.collect_tags(out);
+                rhs.collect_tags(out);
+            }
+        }
+    }
+
+    /// Compiles this expression to a SQL condition on `relative_path.id`.
+    /// Every `?` in the returned string must be bound to the corresponding name
+    /// in the returned vec, in order.
+    pub fn to_sql(&self) -> (String, Vec<&str>) {
+        let mut sql = String::new();
+        let mut binds = Vec::new();
+        self.write_sql(&mut sql, &mut binds);
+        (sql, binds)
+    }
+
+    fn write_sql<'a>(&'a self, sql: &mut String, binds: &mut Vec<&'a str>) {
+        match self {
+            TagExpr::Tag(name) => {
+                sql.push_str(
+                    "EXISTS (SELECT 1
+                             FROM path_tag
+                             INNER JOIN tag ON tag.id = path_tag.tag_id
+                             WHERE path_tag.relative_path_id = relative_path.id
+                                   AND tag.name = ?)",
+                );
+                binds.push(name);
+            }
+            TagExpr::Not(expr) => {
+                sql.push_str("NOT (");
+                expr.write_sql(sql, binds);
+                sql.push(')');
+            }
+            TagExpr::And(lhs, rhs) | TagExpr::Or(lhs, rhs) => {
+                let op = if let TagExpr::And(..) = self {
+                    " AND "
+                } else {
+                    " OR "
+                };
+                sql.push('(');
+                lhs.write_sql(sql, binds);
+                sql.push_str(op);
+                rhs.write_sql(sql, binds);
+                sql.push(')');
+            }
+        }
+    }
+}
+
+/// Accepts either an expression or a list of tags that all need to be present.
+pub fn deserialize_opt_tag_expr<'de, D>(de: D) -> Result<Option<TagExpr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Expr(String),
+        All(Vec<String>),
+    }
+
+    match Repr::deserialize(de)? {
+        Repr::Expr(src) if src.trim().is_empty() => Ok(None),
+        Repr::Expr(src) => TagExpr::parse(&src)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        Repr::All(tags) => Ok(TagExpr::all(tags)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{prelude::*, sqlite::SqliteRow, SqliteConnection};
+
+    fn tag(name: &str) -> TagExpr {
+        TagExpr::Tag(name.to_owned())
+    }
+
+    fn not(expr: TagExpr) -> TagExpr {
+        TagExpr::Not(Box::new(expr))
+    }
+
+    fn and(lhs: TagExpr, rhs: TagExpr) -> TagExpr {
+        TagExpr::And(Box::new(lhs), Box::new(rhs))
+    }
+
+    fn or(lhs: TagExpr, rhs: TagExpr) -> TagExpr {
+        TagExpr::Or(Box::new(lhs), Box::new(rhs))
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            TagExpr::parse("a or b and not c").unwrap(),
+            or(tag("a"), and(tag("b"), not(tag("c"))))
+        );
+        assert_eq!(
+            TagExpr::parse("not a and b").unwrap(),
+            and(not(tag("a")), tag("b"))
+        );
+        assert_eq!(
+            TagExpr::parse("a and b and c").unwrap(),
+            and(and(tag("a"), tag("b")), tag("c"))
+        );
+    }
+
+    #[test]
+    fn parentheses() {
+        assert_eq!(
+            TagExpr::parse("(nature or space) and not nsfw").unwrap(),
+            and(or(tag("nature"), tag("space")), not(tag("nsfw")))
+        );
+        assert_eq!(
+            TagExpr::parse("not (a or b)").unwrap(),
+            not(or(tag("a"), tag("b")))
+        );
+        assert_eq!(TagExpr::parse("((a))").unwrap(), tag("a"));
+    }
+
+    #[test]
+    fn quoted_tags() {
+        assert_eq!(
+            TagExpr::parse(r#""with space" and "or""#).unwrap(),
+            and(tag("with space"), tag("or"))
+        );
+        assert_eq!(
+            TagExpr::parse(r#"a and"(b)""#).unwrap(),
+            and(tag("a"), tag("(b)"))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(TagExpr::parse(""), Err(ParseError::UnexpectedEnd));
+        assert_eq!(TagExpr::parse("a and"), Err(ParseError::UnexpectedEnd));
+        assert_eq!(TagExpr::parse("(a or b"), Err(ParseError::UnexpectedEnd));
+        assert_eq!(
+            TagExpr::parse("a b"),
+            Err(ParseError::UnexpectedToken {
+                token: r#"tag "b""#.to_owned(),
+                pos: 2
+            })
+        );
+        assert_eq!(
+            TagExpr::parse("a and or"),
+            Err(ParseError::UnexpectedToken {
+                token: "'or'".to_owned(),
+                pos: 6
+            })
+        );
+        assert_eq!(
+            TagExpr::parse("a)"),
+            Err(ParseError::UnexpectedToken {
+                token: "')'".to_owned(),
+                pos: 1
+            })
+        );
+        assert_eq!(
+            TagExpr::parse(r#"a and "b"#),
+            Err(ParseError::UnterminatedQuote { pos: 6 })
+        );
+    }
+
+    #[test]
+    fn tags() {
+        let expr = TagExpr::parse("b and (a or not b)").unwrap();
+        assert_eq!(expr.tags(), vec!["a", "b"]);
+        assert_eq!(
+            TagExpr::all(vec!["a".to_owned(), "b".to_owned()]),
+            Some(and(tag("a"), tag("b")))
+        );
+        assert_eq!(TagExpr::all(Vec::new()), None);
+    }
+
+    const EXISTS: &str = "EXISTS (SELECT 1 FROM path_tag \
+                          INNER JOIN tag ON tag.id = path_tag.tag_id \
+                          WHERE path_tag.relative_path_id = relative_path.id \
+                          AND tag.name = ?)";
+
+    fn normalize(sql: &str) -> String {
+        sql.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn sql() {
+        let expr = tag("a");
+        let (sql, binds) = expr.to_sql();
+        assert_eq!(normalize(&sql), EXISTS);
+        assert_eq!(binds, vec!["a"]);
+
+        let expr = TagExpr::parse("(a or b) and not c").unwrap();
+        let (sql, binds) = expr.to_sql();
+        assert_eq!(
+            normalize(&sql),
+            format!("(({e} OR {e}) AND NOT ({e}))", e = EXISTS)
+        );
+        assert_eq!(binds, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn sql_binds_names() {
+        let expr = TagExpr::parse(r#""x') OR 1=1 --" and y"#).unwrap();
+        let (sql, binds) = expr.to_sql();
+        assert_eq!(normalize(&sql), format!("({e} AND {e})", e = EXISTS));
+        assert_eq!(binds, vec!["x') OR 1=1 --", "y"]);
+    }
+
+    async fn matching_files(cxn: &mut SqliteConnection, expr: &str) -> Vec<String> {
+        let expr = TagExpr::parse(expr).unwrap();
+        let (clause, binds) = expr.to_sql();
+        let sql = format!(
+            "SELECT file_path FROM relative_path WHERE {} ORDER BY file_path",
+            clause
+        );
+        let mut query = sqlx::query(&sql);
+        for bind in binds {
+            query = query.bind(bind);
+        }
+        query
+            .try_map(|row: SqliteRow| Ok(row.get("file_path")))
+            .fetch_all(cxn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sql_in_sqlite() {
+        let mut cxn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("./migrations").run(&mut cxn).await.unwrap();
+        for sql in &[
+            "INSERT INTO root(id, root_path) VALUES (1, '/wp')",
+            "INSERT INTO relative_path(id, root_id, file_path, unix_mtime)
+             VALUES (1, 1, 'forest.jpg', 0),
+                    (2, 1, 'nebula.jpg', 0),
+                    (3, 1, 'beach.jpg', 0),
+                    (4, 1, 'untagged.jpg', 0)",
+            "INSERT INTO tag(id, name) VALUES (1, 'nature'), (2, 'space'), (3, 'nsfw')",
+            "INSERT INTO path_tag(relative_path_id, tag_id) VALUES (1, 1), (2, 2), (3, 1), (3, 3)",
+        ] {
+            sqlx::query(sql).execute(&mut cxn).await.unwrap();
+        }
+
+        assert_eq!(
+            matching_files(&mut cxn, "nature").await,
+            vec!["beach.jpg", "forest.jpg"]
+        );
+        assert_eq!(
+            matching_files(&mut cxn, "nature and not nsfw").await,
+            vec!["forest.jpg"]
+        );
+        assert_eq!(
+            matching_files(&mut cxn, "(nature or space) and not nsfw").await,
+            vec!["forest.jpg", "nebula.jpg"]
+        );
+        assert_eq!(
+            matching_files(&mut cxn, "not (nature or space)").await,
+            vec!["untagged.jpg"]
+        );
+        assert_eq!(
+            matching_files(&mut cxn, r#"nature or "x') OR 1=1 --""#).await,
+            vec!["beach.jpg", "forest.jpg"]
+        );
+    }
+
+    #[test]
+    fn deserialize() {
+        #[derive(Deserialize)]
+        struct Cfg {
+            #[serde(default, deserialize_with = "deserialize_opt_tag_expr")]
+            tags: Option<TagExpr>,
+        }
+
+        let parse = |src: &str| toml::from_str::<Cfg>(src).map(|cfg| cfg.tags);
+        assert_eq!(
+            parse(r#"tags = "a and not b""#).unwrap(),
+            Some(and(tag("a"), not(tag("b"))))
+        );
+        assert_eq!(
+            parse(r#"tags = ["a", "b"]"#).unwrap(),
+            Some(and(tag("a"), tag("b")))
+        );
+        assert_eq!(parse(r#"tags = "  ""#).unwrap(), None);
+        assert_eq!(parse("tags = []").unwrap(), None);
+        assert_eq!(parse("").unwrap(), None);
+        assert!(parse(r#"tags = "a and""#).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{data::RelativePath, embedded};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::task;
+
+#[derive(Deserialize, Copy, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TagSourceKind {
+    /// Tags from a running tgcd server
+    Tgcd,
+    /// Every directory between the wallpaper directory and the file
+    Directory,
+    /// Comma separated tags in the `user.xdg.tags` extended attribute
+    Xattr,
+    /// XMP and IPTC keywords embedded in the image
+    Embedded,
+}
+
+pub fn default_tag_sources() -> Vec<TagSourceKind> {
+    vec![TagSourceKind::Tgcd]
+}
+
+/// The file to get tags for.
+pub struct TagInput<'a> {
+    pub absolute: &'a Path,
+    pub relative: &'a RelativePath,
+    pub hash: &'a tgcd::Blake2bHash,
+}
+
+#[async_trait::async_trait]
+pub trait TagSource: Send {
+    async fn tags(&mut self, input: &TagInput<'_>) -> Result<Vec<String>, Error>;
+}
+
+/// All configured tag sources.
+pub struct TagSources(Vec<(TagSourceKind, Box<dyn TagSource>)>);
+
+impl TagSources {
+    async fn connect_source(kind: TagSourceKind) -> Result<Box<dyn TagSource>, Error> {
+        Ok(match kind {
+            TagSourceKind::Tgcd => Box::new(Tgcd::connect().await?),
+            TagSourceKind::Directory => Box::new(Directory),
+            TagSourceKind::Xattr => Box::new(Xattr),
+            TagSourceKind::Embedded => Box::new(Embedded),
+        })
+    }
+
+    /// Sources that can't be set up get logged and skipped.
+    pub async fn connect(kinds: &[TagSourceKind]) -> Self {
+        let mut sources = Vec::new();
+        for kind in kinds {
+            match Self::connect_source(*kind).await {
+                Ok(source) => sources.push((*kind, source)),
+                Err(e) => tracing::warn!("Can't use {:?} tags: {}", kind, e),
+            }
+        }
+
+        Self(sources)
+    }
+
+    /// Like `connect` but fails if any source can't be set up.
+    pub async fn connect_all(kinds: &[TagSourceKind]) -> Result<Self, Error> {
+        let mut sources = Vec::new();
+        for kind in kinds {
+            sources.push((*kind, Self::connect_source(*kind).await?));
+        }
+
+        Ok(Self(sources))
+    }
+
+    /// Tags of all sources merged, failing sources are logged and skipped.
+    pub async fn tags(&mut self, input: &TagInput<'_>) -> Vec<String> {
+        let mut ret = Vec::new();
+        for (kind, source) in &mut self.0 {
+            match source.tags(input).await {
+                Ok(tags) => ret.extend(tags),
+                Err(e) => tracing::warn!(
+                    "Can't get {:?} tags of {}: {}",
+                    kind,
+                    input.relative.as_ref(),
+                    e
+                ),
+            }
+        }
+        ret.sort_unstable();
+        ret.dedup();
+        ret
+    }
+
+    /// Tags of all sources merged, fails if any source fails.
+    pub async fn all_tags(&mut self, input: &TagInput<'_>) -> Result<Vec<String>, Error> {
+        let mut ret = Vec::new();
+        for (_, source) in &mut self.0 {
+            ret.extend(source.tags(input).await?);
+        }
+        ret.sort_unstable();
+        ret.dedup();
+        Ok(ret)
+    }
+}
+
+pub struct Tgcd(tgcd::TgcdClient);
+
+impl Tgcd {
+    pub async fn connect() -> Result<Self, Error> {
+        tgcd::TgcdClient::from_global_config()
+            .await
+            .map_err(Error::new)
+            .map(Self)
+    }
+}
+
+#[async_trait::async_trait]
+impl TagSource for Tgcd {
+    async fn tags(&mut self, input: &TagInput<'_>) -> Result<Vec<String>, Error> {
+        self.0
+            .get_tags(input.hash)
+            .await
+            .map_err(Error::new)
+            .map(|tags| tags.iter().map(|tag| tag.as_ref().to_owned()).collect())
+    }
+}
+
+pub struct Directory;
+
+#[async_trait::async_trait]
+impl TagSource for Directory {
+    async fn tags(&mut self, input: &TagInput<'_>) -> Result<Vec<String>, Error> {
+        Ok(Path::new(input.relative.as_ref())
+            .parent()
+            .into_iter()
+            .flat_map(|parent| parent.iter())
+            .filter_map(|component| component.to_str())
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+}
+
+pub struct Xattr;
+
+#[async_trait::async_trait]
+impl TagSource for Xattr {
+    async fn tags(&mut self, input: &TagInput<'_>) -> Result<Vec<String>, Error> {
+        let value = task::block_in_place(|| crate::unix::getxattr(input.absolute, "user.xdg.tags"))
+            .map_err(Error::new)?;
+        Ok(value
+            .map(|value| {
+                String::from_utf8_lossy(&value)
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+pub struct Embedded;
+
+#[async_trait::async_trait]
+impl TagSource for Embedded {
+    async fn tags(&mut self, input: &TagInput<'_>) -> Result<Vec<String>, Error> {
+        let path = input.absolute.to_owned();
+        task::spawn_blocking(move || embedded::Metadata::read(&path))
+            .await
+            .unwrap()
+            .map_err(Error::new)
+            .map(|meta| meta.keywords())
+    }
+}
+
+#[derive(Debug)]
+pub struct Error(Box<dyn std::error::Error + Sync + Send>);
+
+impl Error {
+    fn new(e: impl Into<Box<dyn std::error::Error + Sync + Send>>) -> Self {
+        Self(e.into())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
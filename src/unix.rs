@@ -3,7 +3,11 @@ use nix::{
     sys::stat::Mode,
     unistd::{self, close},
 };
-use std::{os::unix::io::RawFd, path::Path};
+use std::{
+    ffi::CString,
+    os::unix::{ffi::OsStrExt, io::RawFd},
+    path::Path,
+};
 
 // only _ever_ call this on things you're sure won't fail on
 // Error::InvalidUTF8 or if you're on HP UX
@@ -92,3 +96,45 @@ impl Drop for LockFile {
         let _ = fcntl((self.0).0, FcntlArg::F_SETLK(&flock));
     }
 }
+
+/// Reads the extended attribute `name` of `path`, `None` if it isn't set or the file system
+/// doesn't support extended attributes.
+pub fn getxattr(path: impl AsRef<Path>, name: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+
+    let not_set = |e: std::io::Error| match e.raw_os_error() {
+        Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+        _ => Err(e),
+    };
+
+    loop {
+        // SAFETY: both strings are NUL terminated and a NULL buffer only queries the size
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return not_set(std::io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        // SAFETY: buf is valid for buf.len() bytes
+        let len = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            let e = std::io::Error::last_os_error();
+            // attribute grew in between
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return not_set(e);
+        }
+
+        buf.truncate(len as usize);
+        return Ok(Some(buf));
+    }
+}
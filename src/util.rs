@@ -15,17 +15,37 @@ impl Preempter {
     pub async fn preempt(&mut self) {
         self.tx.send(()).await.unwrap()
     }
+
+    /// Like `preempt` but doesn't wait if a preemption is already pending.
+    pub fn try_preempt(&mut self) {
+        let _ = self.tx.try_send(());
+    }
 }
 
 pub fn preemptible_interval(time: Duration) -> (Preempter, impl Stream<Item = ()>) {
+    preemptible_timer(move || Some(time))
+}
+
+/// Like `preemptible_interval` but asks `next` how long to wait every time it fires or gets
+/// preempted, `None` only waits for preemption.
+pub fn preemptible_timer(
+    mut next: impl FnMut() -> Option<Duration> + Send + 'static,
+) -> (Preempter, impl Stream<Item = ()>) {
     let (preempt_tx, mut preempt_rx) = mpsc::channel(1);
 
     let (mut inner_tx, inner_rx) = mpsc::channel(1);
     task::spawn(async move {
-        let mut timeout = time::delay_for(time);
         loop {
-            future::select(timeout, preempt_rx.next()).await;
-            timeout = time::delay_for(time);
+            match next() {
+                Some(time) => {
+                    future::select(time::delay_for(time), preempt_rx.next()).await;
+                }
+                None => {
+                    if preempt_rx.next().await.is_none() {
+                        break;
+                    }
+                }
+            }
             if let Err(_) = inner_tx.send(()).await {
                 break;
             }
@@ -63,3 +83,80 @@ impl AppPaths {
         })
     }
 }
+
+/// Matches `s` against a shell style glob pattern supporting `*` and `?`.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    let (mut p, mut i) = (0, 0);
+    // position of the last `*` and where in `s` it started matching
+    let mut backtrack = None;
+
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    i = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_star() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything/at/all"));
+        assert!(glob_match("*.part", "a.part"));
+        assert!(glob_match("*.part", "dir/a.part"));
+        assert!(glob_match("a*b*c", "abc"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("**", "abc"));
+        assert!(!glob_match("*.part", "a.partial"));
+        assert!(!glob_match("a*b*c", "aXbY"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(glob_match("?", "a"));
+        assert!(glob_match("DP-?", "DP-1"));
+        assert!(glob_match("?*", "ab"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("?", "ab"));
+        assert!(!glob_match("DP-?", "DP-10"));
+    }
+
+    #[test]
+    fn glob_literal_prefix() {
+        assert!(glob_match("HDMI", "HDMI"));
+        assert!(glob_match("HDMI*", "HDMI-A-1"));
+        assert!(glob_match("tmp/*", "tmp/a.jpg"));
+        assert!(!glob_match("HDMI", "HDMI-A-1"));
+        assert!(!glob_match("HDMI*", "DP-1"));
+        assert!(!glob_match("tmp/*", "a/tmp/b.jpg"));
+    }
+
+    #[test]
+    fn glob_empty() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+        assert!(!glob_match("a", ""));
+    }
+}
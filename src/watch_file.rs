@@ -1,7 +1,12 @@
 use futures_util::stream::{Stream, StreamExt};
-use inotify::{EventMask, Inotify, WatchMask};
-use std::{future::Future, path::Path, time::Duration};
-use tokio::sync::mpsc;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{sync::mpsc, task};
 
 enum WatchResult {
     RxDropped,
@@ -97,3 +102,194 @@ fn watch_file(
     };
     Ok((task, rx))
 }
+
+#[derive(Debug)]
+pub enum DirEvent {
+    /// A file or directory was created, written to or moved into the watched tree
+    Changed(PathBuf),
+    /// A file or directory was deleted or moved out of the watched tree
+    Removed(PathBuf),
+    /// Events were lost, the whole tree needs to be rescanned
+    Overflow,
+}
+
+pub struct DirWatcher {
+    retry_delay: Duration,
+}
+
+impl Default for DirWatcher {
+    fn default() -> Self {
+        Self {
+            retry_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl DirWatcher {
+    /// Recursively watches the directory tree at `path`.
+    pub fn watch(
+        self,
+        path: impl AsRef<Path>,
+    ) -> Result<
+        (
+            impl Future<Output = Result<(), std::io::Error>>,
+            impl Stream<Item = DirEvent>,
+        ),
+        std::io::Error,
+    > {
+        watch_dir(path, self.retry_delay)
+    }
+}
+
+fn dir_mask() -> WatchMask {
+    WatchMask::CREATE
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+}
+
+struct TreeWatch {
+    watches: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl TreeWatch {
+    fn add(&mut self, inotify: &mut Inotify, dir: &Path) {
+        // walking a big tree takes a while
+        task::block_in_place(|| {
+            for ent in walkdir::WalkDir::new(dir)
+                .into_iter()
+                .filter_map(|ent| ent.ok())
+                .filter(|ent| ent.file_type().is_dir())
+            {
+                match inotify.add_watch(ent.path(), dir_mask()) {
+                    Ok(desc) => {
+                        self.watches.insert(desc, ent.into_path());
+                    }
+                    Err(e) => {
+                        tracing::warn!("Can't watch {}: {}", ent.path().display(), e);
+                    }
+                }
+            }
+        })
+    }
+
+    fn remove(&mut self, inotify: &mut Inotify, dir: &Path) {
+        let descs = self
+            .watches
+            .iter()
+            .filter(|(_, path)| path.starts_with(dir))
+            .map(|(desc, _)| desc.clone())
+            .collect::<Vec<_>>();
+        for desc in descs {
+            self.watches.remove(&desc);
+            let _ = inotify.rm_watch(desc);
+        }
+    }
+}
+
+async fn watch_tree(
+    inotify: &mut Inotify,
+    buf: &mut Vec<u8>,
+    tx: &mut mpsc::Sender<DirEvent>,
+    root: &Path,
+    resync: bool,
+) -> Result<WatchResult, std::io::Error> {
+    let mut tree = TreeWatch {
+        watches: HashMap::new(),
+    };
+    tree.add(inotify, root);
+    if tree.watches.is_empty() {
+        return Ok(WatchResult::ParentDeleted);
+    }
+
+    // things might have changed while the directory was gone
+    if resync {
+        if let Err(_) = tx.send(DirEvent::Overflow).await {
+            return Ok(WatchResult::RxDropped);
+        }
+    }
+
+    let mut events = inotify.event_stream(buf)?;
+    while let Some(ev) = events.next().await {
+        let ev = ev?;
+        if ev.mask.contains(EventMask::Q_OVERFLOW) {
+            if let Err(_) = tx.send(DirEvent::Overflow).await {
+                return Ok(WatchResult::RxDropped);
+            }
+            continue;
+        }
+
+        if ev.mask.contains(EventMask::IGNORED) {
+            tree.watches.remove(&ev.wd);
+            if tree.watches.is_empty() {
+                return Ok(WatchResult::ParentDeleted);
+            }
+            continue;
+        }
+
+        let path = match (tree.watches.get(&ev.wd), ev.name) {
+            (Some(parent), Some(name)) => parent.join(name),
+            _ => continue,
+        };
+
+        let is_dir = ev.mask.contains(EventMask::ISDIR);
+        let event = if ev
+            .mask
+            .intersects(EventMask::DELETE | EventMask::MOVED_FROM)
+        {
+            if is_dir {
+                tree.remove(inotify, &path);
+            }
+            DirEvent::Removed(path)
+        } else if is_dir {
+            tree.add(inotify, &path);
+            DirEvent::Changed(path)
+        } else if ev.mask.contains(EventMask::CREATE) {
+            // wait for CLOSE_WRITE
+            continue;
+        } else {
+            DirEvent::Changed(path)
+        };
+
+        if let Err(_) = tx.send(event).await {
+            return Ok(WatchResult::RxDropped);
+        }
+    }
+    panic!("inotify stream stopped for some reason")
+}
+
+fn watch_dir(
+    path: impl AsRef<Path>,
+    retry_delay: Duration,
+) -> Result<
+    (
+        impl Future<Output = Result<(), std::io::Error>>,
+        impl Stream<Item = DirEvent>,
+    ),
+    std::io::Error,
+> {
+    let path = path.as_ref().to_owned();
+    let (mut tx, rx) = mpsc::channel(32);
+    let mut inotify = Inotify::init()?;
+    let task = async move {
+        let mut buf = vec![0; 16 * (1 << 10)];
+        let mut resync = false;
+        loop {
+            tracing::info!("Watching {}", path.display());
+            match watch_tree(&mut inotify, &mut buf, &mut tx, &path, resync).await {
+                Ok(WatchResult::RxDropped) => return Ok(()),
+                Ok(WatchResult::ParentDeleted) => {}
+                Err(e) => {
+                    tracing::error!("Error while watching {}: {}", path.display(), e);
+                }
+            }
+
+            // start from scratch, the old watch descriptors are useless now
+            inotify = Inotify::init()?;
+            resync = true;
+            tokio::time::delay_for(retry_delay).await;
+        }
+    };
+    Ok((task, rx))
+}